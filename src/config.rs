@@ -0,0 +1,132 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::FileEncoding;
+use crate::Indentation;
+
+const CONFIG_FILE: &str = "config";
+
+pub const OPTIONS: [&str; 13] = [
+    "legacy_encoding",
+    "backup",
+    "auto_reload",
+    "sudo_command",
+    "tab_width",
+    "indent_width",
+    "expand_tabs",
+    "detect_indentation",
+    "soft_wrap",
+    "wrap_at_words",
+    "auto_indent",
+    "auto_pairs",
+    "modal",
+];
+
+pub struct Config {
+    pub legacy_encoding: FileEncoding,
+    pub backup: bool,
+    pub auto_reload: bool,
+    pub sudo_command: String,
+    pub indentation: Indentation,
+    pub detect_indentation: bool,
+    pub soft_wrap: bool,
+    pub wrap_at_words: bool,
+    pub auto_indent: bool,
+    pub auto_pairs: bool,
+    pub modal: bool,
+}
+
+impl Config {
+    pub fn load() -> (Self, Vec<String>) {
+        let mut config = Self::default();
+        let mut errors = Vec::new();
+
+        let content = Self::dir()
+            .and_then(|dir| fs::read_to_string(dir.join(CONFIG_FILE)).ok())
+            .unwrap_or_default();
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((key, value)) => config.set(key.trim(), value.trim()),
+                None => Err(format!("expected `key = value`, got `{line}`")),
+            };
+            if let Err(error) = result {
+                errors.push(format!("config line {}: {error}", line_number.saturating_add(1)));
+            }
+        }
+
+        (config, errors)
+    }
+
+    pub fn dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+            return Some(PathBuf::from(dir).join("hecto"));
+        }
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".config").join("hecto"))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "legacy_encoding" => {
+                self.legacy_encoding = FileEncoding::for_label(value)
+                    .ok_or_else(|| format!("unknown encoding `{value}`"))?;
+            },
+            "backup" => self.backup = parse_bool(value)?,
+            "auto_reload" => self.auto_reload = parse_bool(value)?,
+            "sudo_command" => self.sudo_command = value.to_owned(),
+            "tab_width" => self.indentation.tab_width = parse_width(value)?,
+            "indent_width" => self.indentation.indent_width = parse_width(value)?,
+            "expand_tabs" => self.indentation.expand_tabs = parse_bool(value)?,
+            "detect_indentation" => self.detect_indentation = parse_bool(value)?,
+            "soft_wrap" => self.soft_wrap = parse_bool(value)?,
+            "wrap_at_words" => self.wrap_at_words = parse_bool(value)?,
+            "auto_indent" => self.auto_indent = parse_bool(value)?,
+            "auto_pairs" => self.auto_pairs = parse_bool(value)?,
+            "modal" => self.modal = parse_bool(value)?,
+            _ => return Err(format!("unknown option `{key}`")),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            legacy_encoding: FileEncoding::for_label("latin1").unwrap_or_default(),
+            backup: false,
+            auto_reload: true,
+            sudo_command: "sudo tee".to_owned(),
+            indentation: Indentation::default(),
+            detect_indentation: true,
+            soft_wrap: false,
+            wrap_at_words: true,
+            auto_indent: true,
+            auto_pairs: true,
+            modal: false,
+        }
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected true or false, got `{value}`")),
+    }
+}
+
+fn parse_width(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(width) if (1..=16).contains(&width) => Ok(width),
+        _ => Err(format!("expected a width between 1 and 16, got `{value}`")),
+    }
+}
+
+// Underscores are optional, `tabwidth` is `tab_width`
+pub fn option_name(name: &str) -> Option<&'static str> {
+    OPTIONS.iter().find(|option| option.replace('_', "") == name.replace('_', "")).copied()
+}
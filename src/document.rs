@@ -1,10 +1,73 @@
 use crate::Row;
 use crate::Position;
 use crate::SearchDirection;
+use crate::SearchOptions;
 use crate::FileType;
+use crate::FileEncoding;
+use crate::Config;
+use crate::Indentation;
 
+use std::collections::HashSet;
 use std::fs;
-use std::io::{Error, Write};
+use std::ops::Range;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::SystemTime;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+const BRACKETS: [(&str, &str); 3] = [("(", ")"), ("[", "]"), ("{", "}")];
+
+#[derive(PartialEq, Clone, Copy)]
+struct DiskState {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl DiskState {
+    fn read(filename: &str) -> Option<Self> {
+        let metadata = fs::metadata(filename).ok()?;
+        Some(Self {
+            modified: metadata.modified().ok()?,
+            len: metadata.len(),
+        })
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum SortKey {
+    Lexical,
+    Numeric,
+    IgnoreCase,
+    // Rows keep their order, to only reverse them or remove duplicates
+    Unsorted,
+}
+
+pub struct SortOptions {
+    pub key: SortKey,
+    pub unique: bool,
+    pub reverse: bool,
+}
+
+// What `Document::rename` replaced, to go back to it
+pub struct Renamed {
+    filename: Option<String>,
+    file_type: FileType,
+    disk_state: Option<DiskState>,
+}
+
+// Rows `start..start + before.len()` were replaced by `after`
+struct Change {
+    start: usize,
+    before: Vec<String>,
+    after: Vec<String>,
+    at: Position,
+    typing: bool,
+    // Undone and redone together with the change before it
+    joined: bool,
+}
 
 #[derive(Default)]
 pub struct Document {
@@ -12,56 +75,462 @@ pub struct Document {
     rows: Vec<Row>,
     dirty: bool,
     file_type: FileType,
+    encoding: FileEncoding,
+    disk_state: Option<DiskState>,
+    read_only: bool,
+    indentation: Indentation,
+    highlighted_brackets: Vec<usize>,
+    search_rows: Range<usize>,
+    history: Vec<Change>,
+    undone: Vec<Change>,
+    // Length of the history when the group of changes in progress started
+    group: Option<usize>,
 }
 
 impl Document {
-    pub fn open(filename: &str) -> Result<Self, std::io::Error> {
-        let content = fs::read_to_string(filename)?;
-        let mut rows = Vec::new();
+    pub fn open(filename: &str, config: &Config) -> Result<Self, std::io::Error> {
+        let bytes = fs::read(filename)?;
+        let encoding = FileEncoding::detect(&bytes, config.legacy_encoding);
         let file_type = FileType::from(filename);
-        for row_content in content.lines() {
-            let mut row = Row::from(row_content);
-            row.highlight(file_type.highlighting_options(), None);
-            rows.push(row);
-        }
-        Ok(Self { 
+        let mut document = Self { 
             filename: Some(filename.to_string()),
-            rows,
+            rows: Vec::new(),
             dirty: false,
-            file_type: file_type,
-         })
+            file_type,
+            encoding,
+            disk_state: DiskState::read(filename),
+            // Opening for writing without truncating tells if we are allowed to save
+            read_only: fs::OpenOptions::new().write(true).open(filename).is_err(),
+            indentation: config.indentation,
+            highlighted_brackets: Vec::new(),
+            search_rows: 0..0,
+            history: Vec::new(),
+            undone: Vec::new(),
+            group: None,
+        };
+        document.load_rows(&encoding.decode(&bytes));
+        document.detect_indentation(config);
+        Ok(document)
     }
 
-    pub fn save(&mut self) -> Result<(), Error> {
+    pub fn reload(&mut self, config: &Config) -> Result<(), Error> {
         if let Some(filename) = &self.filename {
-            let mut file = fs::File::create(filename)?;
-            for row in &self.rows {
-                file.write_all(row.as_bytes())?;
-                file.write_all(b"\n")?;
-            }
+            let bytes = fs::read(filename)?;
+            self.disk_state = DiskState::read(filename);
+            self.encoding = FileEncoding::detect(&bytes, config.legacy_encoding);
+            self.load_rows(&self.encoding.decode(&bytes));
+            self.detect_indentation(config);
             self.dirty = false;
         }
         Ok(())
     }
 
-    pub fn highlight(&mut self, word: Option<&str>) {
+    fn detect_indentation(&mut self, config: &Config) {
+        self.indentation = if config.detect_indentation {
+            config.indentation.detect(self.rows.iter().map(Row::as_str))
+        } else {
+            config.indentation
+        };
+    }
+
+    pub fn indentation(&self) -> Indentation {
+        self.indentation
+    }
+
+    pub fn set_indentation(&mut self, indentation: Indentation) {
+        self.indentation = indentation;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn changed_on_disk(&self) -> bool {
+        self.filename
+            .as_deref()
+            .is_some_and(|filename| DiskState::read(filename) != self.disk_state)
+    }
+
+    // A new name has its own file type, and nothing on disk was read under it
+    pub fn rename(&mut self, filename: String) -> Renamed {
+        let file_type = FileType::from(&filename[..]);
+        let renamed = Renamed {
+            disk_state: std::mem::replace(&mut self.disk_state, DiskState::read(&filename)),
+            file_type: std::mem::replace(&mut self.file_type, file_type),
+            filename: self.filename.replace(filename),
+        };
+        self.highlight_rows();
+        renamed
+    }
+
+    pub fn undo_rename(&mut self, renamed: Renamed) {
+        self.filename = renamed.filename;
+        self.file_type = renamed.file_type;
+        self.disk_state = renamed.disk_state;
+        self.highlight_rows();
+    }
+
+    fn highlight_rows(&mut self) {
         for row in &mut self.rows {
-            row.highlight(self.file_type.highlighting_options(), word);
+            row.highlight(self.file_type.highlighting_options(), None);
+        }
+    }
+
+    // Our version wins: the change on disk is not reported again
+    pub fn ignore_disk_changes(&mut self) {
+        self.disk_state = self.filename.as_deref().and_then(DiskState::read);
+    }
+
+    fn load_rows(&mut self, content: &str) {
+        self.history.clear();
+        self.undone.clear();
+        self.rows = Vec::new();
+        for row_content in content.lines() {
+            let mut row = Row::from(row_content);
+            row.highlight(self.file_type.highlighting_options(), None);
+            self.rows.push(row);
         }
     }
 
+    pub fn save(&mut self, backup: bool) -> Result<(), Error> {
+        if let Some(filename) = &self.filename {
+            let bytes = self
+                .encoding
+                .encode(&self.contents())
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+            // Saving through a symlink must replace its target, not the link itself
+            let path = fs::canonicalize(filename).unwrap_or_else(|_| PathBuf::from(filename));
+            Self::write_atomically(&path, &bytes, backup)?;
+            self.disk_state = DiskState::read(filename);
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    // Hands the content to an external command, e.g. `sudo tee`, which writes it to the file
+    pub fn save_with_command(&mut self, command: &str) -> Result<(), Error> {
+        let Some(filename) = &self.filename else {
+            return Ok(());
+        };
+        let bytes = self
+            .encoding
+            .encode(&self.contents())
+            .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "empty save command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .arg(filename)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&bytes)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error::other(format!("`{command}` failed with {status}")));
+        }
+
+        self.disk_state = DiskState::read(filename);
+        self.dirty = false;
+        Ok(())
+    }
+
+    // The content is written and synced to a temporary file next to the target,
+    // which is then renamed over it: the original stays intact until the very end
+    fn write_atomically(path: &Path, bytes: &[u8], backup: bool) -> Result<(), Error> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a file path"))?
+            .to_string_lossy();
+        let tmp_path = path.with_file_name(format!(".{file_name}.hecto-{}", std::process::id()));
+        let metadata = fs::metadata(path).ok();
+
+        let mut result = Self::write_synced(&tmp_path, bytes, metadata.as_ref());
+        if result.is_ok() && backup && metadata.is_some() {
+            result = fs::copy(path, path.with_file_name(format!("{file_name}~"))).map(|_| ());
+        }
+        if result.is_ok() {
+            result = fs::rename(&tmp_path, path);
+        }
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return result;
+        }
+
+        // Make the rename itself durable
+        if let Some(directory) = path.parent() {
+            if let Ok(directory) = fs::File::open(directory) {
+                let _ = directory.sync_all();
+            }
+        }
+        Ok(())
+    }
+
+    fn write_synced(path: &Path, bytes: &[u8], metadata: Option<&fs::Metadata>) -> Result<(), Error> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(bytes)?;
+        if let Some(metadata) = metadata {
+            file.set_permissions(metadata.permissions())?;
+            // Only root can give a file away, this is not worth failing the save for
+            let _ = std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()));
+        }
+        file.sync_all()
+    }
+
+    pub fn contents(&self) -> String {
+        let mut content = String::new();
+        for row in &self.rows {
+            content.push_str(row.as_str());
+            content.push('\n');
+        }
+        content
+    }
+
+    pub fn recover(&mut self, content: &str) {
+        self.load_rows(content);
+        self.dirty = true;
+    }
+
+    pub fn encoding(&self) -> String {
+        self.encoding.name()
+    }
+
+    // Decodes the file again when there is nothing to lose, otherwise the
+    // buffer is kept and converted to the new encoding on the next save
+    pub fn set_encoding(&mut self, encoding: FileEncoding) -> bool {
+        self.encoding = encoding;
+        if let (false, Some(filename)) = (self.dirty, &self.filename) {
+            if let Ok(bytes) = fs::read(filename) {
+                let content = encoding.decode(&bytes);
+                self.disk_state = DiskState::read(filename);
+                self.load_rows(&content);
+                return true;
+            }
+        }
+        self.dirty = true;
+        false
+    }
+
+    // Only `rows` get the search matches highlighted, the rows highlighted before
+    // are cleared. `search` holds the matches of `find_all` and their length
+    pub fn highlight(&mut self, search: Option<(&[Vec<usize>], usize)>, rows: Range<usize>) {
+        let opts = self.file_type.highlighting_options();
+        for y in self.search_rows.clone() {
+            if let Some(row) = self.rows.get_mut(y) {
+                row.highlight(opts, None);
+            }
+        }
+        self.search_rows = if search.is_some() { rows } else { 0..0 };
+        for y in self.search_rows.clone() {
+            if let Some(row) = self.rows.get_mut(y) {
+                let matches = search.map(|(matches, len)| (matches.get(y).map_or(&[][..], Vec::as_slice), len));
+                row.highlight(opts, matches);
+            }
+        }
+    }
+
+    // Starts of the matches in each row
+    pub fn find_all(&self, query: &str, options: &SearchOptions) -> Vec<Vec<usize>> {
+        self.rows.iter().map(|row| row.find_all(query, options)).collect()
+    }
+
+    // Position of the bracket paired with the one at `at`, looking across at
+    // most `max_rows` rows
+    pub fn matching_bracket(&self, at: &Position, max_rows: usize) -> Option<Position> {
+        let row = self.rows.get(at.y)?;
+        let grapheme = row.grapheme(at.x)?;
+        if !row.is_code(at.x) {
+            return None;
+        }
+        let (open, close, forward) = BRACKETS.iter().find_map(|(open, close)| {
+            if grapheme == *open {
+                Some((*open, *close, true))
+            } else if grapheme == *close {
+                Some((*open, *close, false))
+            } else {
+                None
+            }
+        })?;
+
+        let (same, paired) = if forward { (open, close) } else { (close, open) };
+        let mut depth: usize = 0;
+        let mut y = at.y;
+        while y.abs_diff(at.y) <= max_rows {
+            let row = self.rows.get(y)?;
+            let mut step = |(x, grapheme): (usize, &str)| {
+                if !row.is_code(x) {
+                    return None;
+                }
+                if grapheme == same {
+                    depth = depth.saturating_add(1);
+                } else if grapheme == paired {
+                    if depth == 0 {
+                        return Some(x);
+                    }
+                    depth -= 1;
+                }
+                None
+            };
+            let graphemes = row.as_str().graphemes(true);
+            let found = if forward {
+                let start = if y == at.y { at.x.saturating_add(1) } else { 0 };
+                graphemes.enumerate().skip(start).find_map(&mut step)
+            } else {
+                let end = if y == at.y { at.x } else { row.len() };
+                (0..row.len()).rev().zip(graphemes.rev()).skip(row.len().saturating_sub(end)).find_map(&mut step)
+            };
+            if let Some(x) = found {
+                return Some(Position { x, y });
+            }
+            if forward {
+                y = y.saturating_add(1);
+            } else {
+                y = y.checked_sub(1)?;
+            }
+        }
+        None
+    }
+
+    pub fn highlight_brackets(&mut self, positions: &[Position]) {
+        for y in self.highlighted_brackets.drain(..) {
+            if let Some(row) = self.rows.get_mut(y) {
+                row.highlight(self.file_type.highlighting_options(), None);
+            }
+        }
+        for position in positions {
+            if let Some(row) = self.rows.get_mut(position.y) {
+                row.highlight_bracket(position.x);
+                self.highlighted_brackets.push(position.y);
+            }
+        }
+    }
+
+    fn lines(&self, rows: Range<usize>) -> Vec<String> {
+        self.rows[rows].iter().map(|row| row.as_str().to_owned()).collect()
+    }
+
+    // Runs an edit that stays within `rows`, though it may add or remove rows in
+    // that range, and records it as one undoable change
+    fn record<R>(&mut self, rows: Range<usize>, at: &Position, typing: bool, edit: impl FnOnce(&mut Self) -> R) -> R {
+        let start = std::cmp::min(rows.start, self.rows.len());
+        let end = std::cmp::min(rows.end, self.rows.len()).max(start);
+        let before = self.lines(start..end);
+        let len_before = self.rows.len();
+
+        let result = edit(self);
+
+        let end_after = end.saturating_add(self.rows.len()).saturating_sub(len_before);
+        let after = self.lines(start..end_after);
+        if before == after {
+            return result;
+        }
+        self.dirty = true;
+        self.undone.clear();
+
+        // Typing on a single row is undone in one go
+        if let Some(last) = self.history.last_mut().filter(|_| self.group.is_none()) {
+            if typing && last.typing && last.start == start && last.after == before && before.len() == 1 && after.len() == 1 {
+                last.after = after;
+                return result;
+            }
+        }
+        self.history.push(Change {
+            start,
+            before,
+            after,
+            at: at.clone(),
+            typing,
+            joined: self.group.is_some_and(|start| self.history.len() > start),
+        });
+        result
+    }
+
+    fn splice_rows(&mut self, start: usize, count: usize, lines: &[String]) {
+        let opts = self.file_type.highlighting_options();
+        let rows = lines.iter().map(|line| {
+            let mut row = Row::from(&line[..]);
+            row.highlight(opts, None);
+            row
+        });
+        self.rows.splice(start..start.saturating_add(count), rows);
+        self.dirty = true;
+    }
+
+    // Number of changes that can be undone, which tells whether an edit happened
+    pub fn changes(&self) -> usize {
+        self.history.len()
+    }
+
+    // Changes made until `end_group` are undone and redone together
+    pub fn begin_group(&mut self) {
+        self.group = Some(self.history.len());
+    }
+
+    pub fn end_group(&mut self) {
+        self.group = None;
+    }
+
+    // Returns where the undone change happened
+    pub fn undo(&mut self) -> Option<Position> {
+        if self.read_only {
+            return None;
+        }
+        let mut at = None;
+        while let Some(change) = self.history.pop() {
+            self.splice_rows(change.start, change.after.len(), &change.before);
+            at = Some(change.at.clone());
+            let joined = change.joined;
+            self.undone.push(change);
+            if !joined {
+                break;
+            }
+        }
+        at
+    }
+
+    pub fn redo(&mut self) -> Option<Position> {
+        if self.read_only {
+            return None;
+        }
+        let mut at = None;
+        while let Some(change) = self.undone.pop() {
+            self.splice_rows(change.start, change.before.len(), &change.after);
+            at = Some(change.at.clone());
+            self.history.push(change);
+            if !self.undone.last().is_some_and(|next| next.joined) {
+                break;
+            }
+        }
+        at
+    }
+
     pub fn insert(&mut self, at: &Position, c: char) {
-        if at.y > self.len() {
+        if at.y > self.len() || self.read_only {
             return;
         } 
-        
-        self.dirty = true;
 
         if c == '\n' {
-            self.insert_newline(at);
+            self.insert_newline(at, false);
             return;
         }
 
+        self.record(at.y..at.y.saturating_add(1), at, true, |document| document.insert_char(at, c));
+    }
+
+    fn insert_char(&mut self, at: &Position, c: char) {
         if at.y == self.len() {
             let mut row = Row::default();
             row.insert(0, c);
@@ -74,33 +543,397 @@ impl Document {
         }
     }
 
-    pub fn insert_newline(&mut self, at: &Position) {
+    // Returns the cursor column on the new row, after the indentation when `auto_indent` is set
+    pub fn insert_newline(&mut self, at: &Position, auto_indent: bool) -> usize {
+        if at.y > self.len() || self.read_only {
+            return 0;
+        }
+        self.record(at.y..at.y.saturating_add(1), at, false, |document| document.split_row(at, auto_indent))
+    }
+
+    fn split_row(&mut self, at: &Position, auto_indent: bool) -> usize {
         if at.y == self.len() {
             self.rows.push(Row::default());
-            return;
+            return 0;
         }
 
         let current_row = &mut self.rows[at.y];
+        let indent = current_row.leading_whitespace().to_owned();
+        let in_indentation = at.x < Row::from(&indent[..]).len();
         let mut new_row = current_row.split(at.x);
+        let mut cursor_x = 0;
+
+        if auto_indent && in_indentation {
+            cursor_x = Row::from(new_row.leading_whitespace()).len();
+        } else if auto_indent {
+            let rest = new_row.as_str().trim_start().to_owned();
+            let opens_block = current_row
+                .as_str()
+                .trim_end()
+                .chars()
+                .last()
+                .is_some_and(|c| self.file_type.indents_after(c));
+            let closes_block = rest.chars().next().is_some_and(|c| self.file_type.dedents_on(c));
 
+            if opens_block {
+                let inner_indent = format!("{indent}{}", self.indentation.unit());
+                cursor_x = Row::from(&inner_indent[..]).len();
+                if closes_block {
+                    // The closer goes one row further, back at the outer indentation
+                    let mut inner_row = Row::from(&inner_indent[..]);
+                    inner_row.highlight(self.file_type.highlighting_options(), None);
+                    self.rows.insert(at.y + 1, inner_row);
+                    new_row = Row::from(&format!("{indent}{rest}")[..]);
+                    new_row.highlight(self.file_type.highlighting_options(), None);
+                    self.rows[at.y].highlight(self.file_type.highlighting_options(), None);
+                    self.rows.insert(at.y + 2, new_row);
+                    return cursor_x;
+                }
+                new_row = Row::from(&format!("{inner_indent}{rest}")[..]);
+            } else {
+                cursor_x = Row::from(&indent[..]).len();
+                new_row = Row::from(&format!("{indent}{rest}")[..]);
+            }
+        }
+
+        let current_row = &mut self.rows[at.y];
         current_row.highlight(self.file_type.highlighting_options(), None);
         new_row.highlight(self.file_type.highlighting_options(), None);
 
         self.rows.insert(at.y + 1, new_row);
+        cursor_x
+    }
+
+    // Adds one indentation unit to the non-blank rows, returns how many graphemes each row got
+    pub fn indent_rows(&mut self, rows: Range<usize>) -> Vec<usize> {
+        if self.read_only {
+            return Vec::new();
+        }
+        let unit = self.indentation.unit();
+        let at = Position { x: 0, y: rows.start };
+        self.record(rows.clone(), &at, false, |document| {
+            rows.filter_map(|y| {
+                let line = document.rows.get(y)?.as_str().to_owned();
+                if line.trim().is_empty() {
+                    return Some(0);
+                }
+                document.replace_row(y, &format!("{unit}{line}"));
+                Some(unit.len())
+            })
+            .collect()
+        })
+    }
+
+    // Removes one indentation unit from each row, returns how many graphemes each row lost
+    pub fn outdent_rows(&mut self, rows: Range<usize>) -> Vec<usize> {
+        if self.read_only {
+            return Vec::new();
+        }
+        let at = Position { x: 0, y: rows.start };
+        self.record(rows.clone(), &at, false, |document| {
+            rows.map(|y| document.outdent_row(y)).collect()
+        })
+    }
+
+    fn outdent_row(&mut self, y: usize) -> usize {
+        let Some(row) = self.rows.get_mut(y) else {
+            return 0;
+        };
+        let count = if row.as_str().starts_with('\t') {
+            1
+        } else {
+            let spaces = row.as_str().len().saturating_sub(row.as_str().trim_start_matches(' ').len());
+            match spaces % self.indentation.indent_width {
+                0 => std::cmp::min(spaces, self.indentation.indent_width),
+                remainder => remainder,
+            }
+        };
+        for _ in 0..count {
+            row.delete(0);
+        }
+        if count > 0 {
+            row.highlight(self.file_type.highlighting_options(), None);
+        }
+        count
+    }
+
+    // Comments the rows out at their smallest indentation, or uncomments them when
+    // they all are comments already. Returns, for each row, the column where the
+    // comment token went and how many graphemes were added (or removed), None
+    // without comment syntax
+    pub fn toggle_comment(&mut self, rows: Range<usize>) -> Option<Vec<(usize, isize)>> {
+        let opts = self.file_type.highlighting_options();
+        let (open, close) = match (opts.line_comment(), opts.block_comment()) {
+            (Some(token), _) => (format!("{token} "), String::new()),
+            (None, Some((open, close))) => (format!("{open} "), format!(" {close}")),
+            (None, None) => return None,
+        };
+        let rows = std::cmp::min(rows.start, self.rows.len())..std::cmp::min(rows.end, self.rows.len());
+        // Indentation is spaces and tabs only, its bytes are graphemes
+        let lines: Vec<(String, usize)> = self.rows[rows.clone()]
+            .iter()
+            .map(|row| (row.as_str().to_owned(), row.leading_whitespace().len()))
+            .collect();
+        let is_blank = |line: &String| line.trim().is_empty();
+
+        let Some(indent) = lines
+            .iter()
+            .filter(|(line, _)| !is_blank(line))
+            .map(|(_, indent)| *indent)
+            .min() else {
+            return Some(Vec::new());
+        };
+        // The token must stand alone, `///` or `//!` start a doc comment rather than comment one out
+        let commented = lines.iter().filter(|(line, _)| !is_blank(line)).all(|(line, indent)| {
+            let content = line[*indent..].trim_end();
+            content.strip_prefix(open.trim_end()).is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+                && content.ends_with(close.trim_start())
+        });
+
+        let at = Position { x: 0, y: rows.start };
+        let mut moves = Vec::new();
+        self.record(rows.clone(), &at, false, |document| {
+            for (y, (line, line_indent)) in rows.zip(lines) {
+                if is_blank(&line) {
+                    moves.push((0, 0));
+                    continue;
+                }
+                let (new_line, column, delta) = if commented {
+                    let (indentation, content) = line.split_at(line_indent);
+                    let uncommented = content.strip_prefix(&open[..]).or_else(|| content.strip_prefix(open.trim_end())).unwrap_or(content);
+                    let removed = content[..content.len().saturating_sub(uncommented.len())].graphemes(true).count();
+                    let uncommented = uncommented.strip_suffix(&close[..]).or_else(|| uncommented.strip_suffix(close.trim_start())).unwrap_or(uncommented);
+                    (format!("{indentation}{uncommented}"), line_indent, -(removed as isize))
+                } else {
+                    let (outer, inner) = line.split_at(indent);
+                    (format!("{outer}{open}{inner}{close}"), indent, open.graphemes(true).count() as isize)
+                };
+                document.replace_row(y, &new_line);
+                moves.push((column, delta));
+            }
+        });
+        Some(moves)
+    }
+
+    fn replace_row(&mut self, y: usize, content: &str) {
+        if let Some(row) = self.rows.get_mut(y) {
+            *row = Row::from(content);
+            row.highlight(self.file_type.highlighting_options(), None);
+        }
+    }
+
+    // Returns false when there is nothing to copy, e.g. past the end of the file
+    pub fn duplicate_rows(&mut self, rows: Range<usize>) -> bool {
+        if self.read_only || rows.end > self.rows.len() {
+            return false;
+        }
+        let lines = self.lines(rows.clone());
+        let at = Position { x: 0, y: rows.start };
+        self.record(rows.clone(), &at, false, |document| {
+            document.splice_rows(rows.end, 0, &lines);
+        });
+        true
+    }
+
+    // Swaps the rows with the one above or below them, returns false at the edges of the file
+    pub fn move_rows(&mut self, rows: Range<usize>, up: bool) -> bool {
+        if self.read_only || rows.end > self.rows.len() || (up && rows.start == 0) || (!up && rows.end == self.rows.len()) {
+            return false;
+        }
+        let range = if up {
+            rows.start.saturating_sub(1)..rows.end
+        } else {
+            rows.start..rows.end.saturating_add(1)
+        };
+        let at = Position { x: 0, y: rows.start };
+        self.record(range.clone(), &at, false, |document| {
+            let mut lines = document.lines(range.clone());
+            if up {
+                lines.rotate_left(1);
+            } else {
+                lines.rotate_right(1);
+            }
+            document.splice_rows(range.start, range.len(), &lines);
+        });
+        true
+    }
+
+    // Joins the rows into one, or the row with the next one, with a single space
+    // in place of the whitespace around each join. Returns the last join position
+    pub fn join_rows(&mut self, rows: Range<usize>) -> Option<Position> {
+        let rows = rows.start..std::cmp::max(rows.end, rows.start.saturating_add(2));
+        if self.read_only || rows.end > self.rows.len() {
+            return None;
+        }
+        let lines = self.lines(rows.clone());
+        let mut joined = String::new();
+        let mut join_x = 0;
+        for (index, line) in lines.iter().enumerate() {
+            if index == 0 {
+                joined.push_str(line.trim_end());
+                continue;
+            }
+            let joined_before = joined.len();
+            joined = joined.trim_end().to_owned();
+            if joined.len() < joined_before || (!joined.is_empty() && !line.trim().is_empty()) {
+                joined.push(' ');
+            }
+            join_x = joined.graphemes(true).count();
+            joined.push_str(line.trim());
+        }
+        let at = Position { x: join_x, y: rows.start };
+        self.record(rows.clone(), &at, false, |document| {
+            document.splice_rows(rows.start, rows.len(), &[joined]);
+        });
+        Some(at)
+    }
+
+    pub fn delete_rows(&mut self, rows: Range<usize>) {
+        if self.read_only {
+            return;
+        }
+        let rows = std::cmp::min(rows.start, self.rows.len())..std::cmp::min(rows.end, self.rows.len());
+        let at = Position { x: 0, y: rows.start };
+        self.record(rows.clone(), &at, false, |document| {
+            document.splice_rows(rows.start, rows.len(), &[]);
+        });
+    }
+
+    // Text from `start` up to `end`, rows joined with newlines
+    pub fn text(&self, start: &Position, end: &Position) -> String {
+        let (start, end) = self.ordered_range(start, end);
+        let mut text = String::new();
+        for y in start.y..=end.y {
+            let Some(row) = self.rows.get(y) else {
+                break;
+            };
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            text.extend(row.as_str().graphemes(true).skip(from).take(to.saturating_sub(from)));
+            if y < end.y {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    // Removes the text from `start` up to `end` as one change
+    pub fn delete_range(&mut self, start: &Position, end: &Position) {
+        if self.read_only || self.rows.is_empty() {
+            return;
+        }
+        let (start, end) = self.ordered_range(start, end);
+        let prefix: String = self.rows[start.y].as_str().graphemes(true).take(start.x).collect();
+        let suffix: String = self.rows[end.y].as_str().graphemes(true).skip(end.x).collect();
+        let rows = start.y..end.y.saturating_add(1);
+        self.record(rows.clone(), &start, false, |document| {
+            document.splice_rows(rows.start, rows.len(), &[format!("{prefix}{suffix}")]);
+        });
+    }
+
+    // Inserts text that may span rows as one change, returns the position right after it
+    // The positions in document order, moved within the document. Past the
+    // last row is its end
+    fn ordered_range(&self, start: &Position, end: &Position) -> (Position, Position) {
+        let last_row = self.rows.len().saturating_sub(1);
+        let clamp = |position: &Position| {
+            if position.y > last_row {
+                return Position { x: self.rows.get(last_row).map_or(0, Row::len), y: last_row };
+            }
+            let x = std::cmp::min(position.x, self.rows.get(position.y).map_or(0, Row::len));
+            Position { x, y: position.y }
+        };
+        let (start, end) = (clamp(start), clamp(end));
+        if (start.y, start.x) <= (end.y, end.x) {
+            (start, end)
+        } else {
+            (end, start)
+        }
+    }
+
+    pub fn insert_text(&mut self, at: &Position, text: &str) -> Position {
+        if self.read_only || at.y > self.rows.len() {
+            return at.clone();
+        }
+        let line = self.rows.get(at.y).map_or("", Row::as_str);
+        let split = line.grapheme_indices(true).nth(at.x).map_or(line.len(), |(index, _)| index);
+        let (prefix, suffix) = line.split_at(split);
+
+        let mut lines: Vec<String> = text.split('\n').map(ToOwned::to_owned).collect();
+        let last = lines.len().saturating_sub(1);
+        let end = Position {
+            x: lines[last].graphemes(true).count().saturating_add(if last == 0 { at.x } else { 0 }),
+            y: at.y.saturating_add(last),
+        };
+        lines[0].insert_str(0, prefix);
+        lines[last].push_str(suffix);
+
+        let count = usize::from(at.y < self.rows.len());
+        self.record(at.y..at.y.saturating_add(1), at, false, |document| {
+            document.splice_rows(at.y, count, &lines);
+        });
+        end
+    }
+
+    pub fn sort_rows(&mut self, rows: Range<usize>, options: &SortOptions) {
+        if self.read_only {
+            return;
+        }
+        let rows = std::cmp::min(rows.start, self.rows.len())..std::cmp::min(rows.end, self.rows.len());
+        let mut lines = self.lines(rows.clone());
+
+        match options.key {
+            SortKey::Lexical => lines.sort(),
+            SortKey::IgnoreCase => lines.sort_by_key(|line| line.to_lowercase()),
+            SortKey::Numeric => lines.sort_by(|a, b| leading_number(a).total_cmp(&leading_number(b))),
+            SortKey::Unsorted => (),
+        }
+        if options.unique {
+            let same = |a: &String, b: &String| match options.key {
+                SortKey::IgnoreCase => a.to_lowercase() == b.to_lowercase(),
+                SortKey::Numeric => leading_number(a) == leading_number(b),
+                _ => a == b,
+            };
+            if options.key == SortKey::Unsorted {
+                // Keeps the first of equal lines wherever they are
+                let mut seen = HashSet::new();
+                lines.retain(|line| seen.insert(line.clone()));
+            } else {
+                lines.dedup_by(|a, b| same(a, b));
+            }
+        }
+        if options.reverse {
+            lines.reverse();
+        }
+
+        let at = Position { x: 0, y: rows.start };
+        self.record(rows.clone(), &at, false, |document| {
+            document.splice_rows(rows.start, rows.len(), &lines);
+        });
+    }
+
+    pub fn closes_block(&self, c: char) -> bool {
+        self.file_type.dedents_on(c)
+    }
+
+    pub fn auto_pairs(&self) -> &Vec<(char, char)> {
+        self.file_type.auto_pairs()
     }
 
     pub fn delete(&mut self, at: &Position) {
-        let len = self.len();
-        if at.y >= len {
+        if at.y >= self.len() || self.read_only {
             return;
         }
+        self.record(at.y..at.y.saturating_add(2), at, true, |document| document.delete_char(at));
+    }
 
-        self.dirty = true;
+    fn delete_char(&mut self, at: &Position) {
+        let len = self.len();
         if at.x == self.rows[at.y].len() && at.y < len - 1 {
             let next_row = self.rows.remove(at.y + 1);
             let row = &mut self.rows[at.y];
-            row.highlight(self.file_type.highlighting_options(), None);
             row.append(&next_row);
+            row.highlight(self.file_type.highlighting_options(), None);
         } else {
             let row = &mut self.rows[at.y];
             row.delete(at.x);
@@ -120,7 +953,7 @@ impl Document {
         self.dirty
     }
 
-    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
+    pub fn find(&self, query: &str, at: &Position, direction: SearchDirection, options: &SearchOptions) -> Option<Position> {
         if at.y >= self.rows.len() {
             return None;
         }
@@ -138,7 +971,7 @@ impl Document {
 
         for _ in start..end {
             if let Some(row) = self.rows.get(position.y) {
-                if let Some(x) = row.find(&query, position.x, direction) {
+                if let Some(x) = row.find(query, position.x, direction, options) {
                     position.x = x;
                     return Some(position);
                 }
@@ -167,3 +1000,13 @@ impl Document {
         self.rows.len()
     }
 }
+
+// Like `sort -n`, lines not starting with a number count as 0
+fn leading_number(line: &str) -> f64 {
+    let line = line.trim_start();
+    let end = line
+        .char_indices()
+        .find(|(index, c)| !(c.is_ascii_digit() || *c == '.' || (*index == 0 && (*c == '-' || *c == '+'))))
+        .map_or(line.len(), |(index, _)| index);
+    line[..end].parse().unwrap_or(0.0)
+}
@@ -1,22 +1,62 @@
 
+use std::collections::HashMap;
+use std::ops::Range;
 use std::time::Instant;
 use std::time::Duration;
 
+use rhai::Dynamic;
+use termion::event::Event;
 use termion::event::Key;
+use termion::event::MouseButton;
+use termion::event::MouseEvent;
 use termion::color;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 use crate::Terminal;
 use crate::Document;
+use crate::SortKey;
+use crate::SortOptions;
 use crate::Row;
+use crate::Config;
+use crate::config;
+use crate::FileEncoding;
+use crate::Recovery;
+use crate::recovery;
+use crate::Vi;
+use crate::Keymap;
+use crate::Prompt;
+use crate::Macros;
+use crate::macros;
+use crate::Scripts;
+use crate::scripts::Request;
+use crate::keymap;
+use crate::keymap::Action;
+use crate::keymap::Lookup;
+use crate::vi;
+use crate::vi::Command;
+use crate::vi::Mode;
+use crate::vi::Motion;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const STATUS_BG_COLOR: color::Rgb = color::Rgb(239, 239, 239);
 const STATUS_FG_COLOR: color::Rgb = color::Rgb(63, 63, 63);
 const QUIT_TIMES: u8 = 3;
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(2);
+// Without input the loop still comes round this often, to write the recovery
+// file and see changes on disk
+const IDLE_INTERVAL: Duration = Duration::from_secs(1);
 
 const STATUS_BAR_LENGTH: usize = 40;
+const PROMPT_HISTORY_LENGTH: usize = 100;
+const WHEEL_SCROLL_ROWS: usize = 3;
+// Rows searched for the bracket matching the one at the cursor, beyond the screen
+const BRACKET_SEARCH_ROWS: usize = 200;
 
-#[derive(Default, Clone)]
+// Command line commands besides the named editor commands
+const COMMANDS: [&str; 9] = ["goto", "open", "open!", "q", "q!", "quit", "set", "w", "wq"];
+
+#[derive(Default, Clone, PartialEq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -28,6 +68,34 @@ pub enum SearchDirection {
     Backward,
 }
 
+#[derive(Default, Copy, Clone)]
+pub struct SearchOptions {
+    pub ignore_case: bool,
+    // Ignores case unless the query has capitals
+    pub smart_case: bool,
+    pub whole_word: bool,
+}
+
+impl SearchOptions {
+    pub fn ignores_case(&self, query: &str) -> bool {
+        if self.smart_case {
+            !query.chars().any(char::is_uppercase)
+        } else {
+            self.ignore_case
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = String::new();
+        for (enabled, name) in [(self.ignore_case, "ignore case"), (self.smart_case, "smart case"), (self.whole_word, "whole word")] {
+            if enabled {
+                flags.push_str(&format!("[{name}] "));
+            }
+        }
+        flags
+    }
+}
+
 struct StatusMessage {
     text: String,
     timestamp: Instant,
@@ -47,12 +115,37 @@ pub struct Editor {
     quit_times: u8,
     terminal: Terminal,
     cursor_position: Position,
+    // The selection runs from here to the cursor
+    selection_anchor: Option<Position>,
+    // Cursors besides `cursor_position`, in document order
+    cursors: Vec<Position>,
+    // Where the left button went down, a drag selects from there
+    drag_start: Option<Position>,
     offset: Position,
+    // Screen line of the row at `offset.y` shown first when soft wrapping
+    wrap_offset: usize,
     document: Document,
     status_message: StatusMessage,
+    config: Config,
+    last_recovery_write: Instant,
+    vi: Vi,
+    keymap: Keymap,
+    macros: Macros,
+    scripts: Scripts,
+    prompt_history: HashMap<String, Vec<String>>,
+    // Screen column of the cursor while a prompt is open
+    prompt_cursor: Option<usize>,
+    // Shown before the prompt text, e.g. the active search options
+    prompt_flags: String,
+    // Shown after the prompt text, e.g. the search match count
+    prompt_info: String,
+    search_options: SearchOptions,
 }
 impl Editor {
     pub fn run(&mut self) {
+        self.check_recovery();
+        let result = self.scripts.run(&mut self.document, &mut self.cursor_position);
+        self.script_done(result);
         
         while !self.should_quit {
             if let Err(error) = self.refresh_screen() {
@@ -61,27 +154,70 @@ impl Editor {
             if let Err(error) = self.process_keypress() {
                 self.exit_on_error(error);
             }
+            if !self.should_quit {
+                self.check_disk_changes();
+            }
+            if self.last_recovery_write.elapsed() >= RECOVERY_INTERVAL {
+                self.write_recovery();
+            }
         }
 
         self.exit();
     }
 
     pub fn default() -> Self {
-        let args: Vec<_> = std::env::args().collect();
-        let mut initial_status = "HELP: Ctrl-F: find | Ctrl-S = save | Ctrl-Q = quit".to_owned();
+        let mut read_only = false;
+        let mut filename = None;
+        for arg in std::env::args().skip(1) {
+            match arg.as_str() {
+                "-R" | "--readonly" => read_only = true,
+                _ => filename = Some(arg),
+            }
+        }
+        let (config, config_errors) = Config::load();
+        let (keymap, keymap_errors) = Keymap::load();
+        let (macros, macros_errors) = Macros::load();
+        let (scripts, scripts_errors) = Scripts::load();
+        let mut initial_status = format!(
+            "HELP: {}: find | {} = save | {} = quit",
+            keymap.keys_for(Action::Search),
+            keymap.keys_for(Action::Save),
+            keymap.keys_for(Action::Quit)
+        );
+        let errors: Vec<_> = config_errors.iter().chain(keymap_errors.iter()).chain(macros_errors.iter()).chain(scripts_errors.iter()).collect();
+        if let Some(error) = errors.first() {
+            initial_status = match errors.len() {
+                1 => format!("ERR: {error}"),
+                count => format!("ERR: {error} (and {} more)", count.saturating_sub(1)),
+            };
+        }
 
-        let document = if let Some(filename) = args.get(1) {
-            if let Ok(document) = Document::open(filename) {
-                document
-            } else {
-                initial_status = format!("ERR: Could not open file {filename}, creating it");
-                let mut document = Document::default();
-                document.filename = Some(filename.clone());
-                document
+        let mut document = if let Some(filename) = &filename {
+            match Document::open(filename, &config) {
+                Ok(document) => document,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                    initial_status = format!("ERR: Could not open file {filename}, creating it");
+                    let mut document = Document::default();
+                    document.filename = Some(filename.clone());
+                    document.set_indentation(config.indentation);
+                    document
+                },
+                Err(error) => {
+                    // Without a filename a save has to go through "Save as" and can't clobber the file
+                    initial_status = format!("ERR: Could not open file {filename}: {error}");
+                    let mut document = Document::default();
+                    document.set_indentation(config.indentation);
+                    document
+                },
             }
         } else {
-            Document::default()
+            let mut document = Document::default();
+            document.set_indentation(config.indentation);
+            document
         };
+        if read_only {
+            document.set_read_only(true);
+        }
 
         Self { 
             should_quit: false,
@@ -89,8 +225,23 @@ impl Editor {
             terminal: Terminal::default().expect("Failed to create the terminal"),
             document,
             offset: Position::default(),
+            wrap_offset: 0,
             cursor_position: Position::default(),
+            selection_anchor: None,
+            cursors: Vec::new(),
+            drag_start: None,
             status_message: StatusMessage::from(initial_status),
+            config,
+            last_recovery_write: Instant::now(),
+            vi: Vi::default(),
+            keymap,
+            macros,
+            scripts,
+            prompt_history: HashMap::new(),
+            prompt_cursor: None,
+            prompt_flags: String::new(),
+            prompt_info: String::new(),
+            search_options: SearchOptions::default(),
         }
     }
 
@@ -102,10 +253,19 @@ impl Editor {
         self.draw_status_bar();
         self.draw_message_bar();
         
-        Terminal::cursor_position(&Position {
-            x: self.cursor_position.x.saturating_sub(self.offset.x),
-            y: self.cursor_position.y.saturating_sub(self.offset.y),
-        });
+        if let Some(column) = self.prompt_cursor {
+            Terminal::cursor_position(&Position {
+                x: std::cmp::min(column, (self.terminal.size().width as usize).saturating_sub(1)),
+                y: (self.terminal.size().height as usize).saturating_add(1),
+            });
+        } else if self.config.soft_wrap {
+            Terminal::cursor_position(&self.wrapped_cursor_position());
+        } else {
+            Terminal::cursor_position(&Position {
+                x: self.cursor_column().saturating_sub(self.offset.x),
+                y: self.cursor_position.y.saturating_sub(self.offset.y),
+            });
+        }
         Terminal::show_cursor();
         Terminal::flush()
 
@@ -113,11 +273,17 @@ impl Editor {
     }
 
     fn draw_rows(&self) {
+        if self.config.soft_wrap {
+            self.draw_wrapped_rows();
+            return;
+        }
+
         let height = self.terminal.size().height;
         for terminal_row in 0..height {
             Terminal::clear_current_line();
-            if let Some(row) = self.document.row((terminal_row as usize).saturating_add(self.offset.y)) {
-                self.draw_row(row);
+            let y = (terminal_row as usize).saturating_add(self.offset.y);
+            if let Some(row) = self.document.row(y) {
+                self.draw_row(row, y);
             } else if terminal_row == height / 3 && self.document.is_empty() {
                 self.draw_welcome_message();
             } else {
@@ -126,16 +292,136 @@ impl Editor {
         }
     }
 
-    fn draw_row(&self, row: &Row) {
+    fn draw_row(&self, row: &Row, y: usize) {
         let width = self.terminal.size().width as usize;
 
         let start = self.offset.x;
         let end = self.offset.x.saturating_add(width);
 
-        let row = row.render(start, end);
+        let row = row.render(start, end, self.document.indentation().tab_width, &self.selection_in_row(y), &self.cursors_in_row(y));
         println!("{row}\r");
     }
 
+    fn draw_wrapped_rows(&self) {
+        let height = self.terminal.size().height as usize;
+        let tab_width = self.document.indentation().tab_width;
+        let mut y = self.offset.y;
+        let mut line = self.wrap_offset;
+        let mut terminal_row = 0;
+
+        while terminal_row < height {
+            Terminal::clear_current_line();
+            if let Some(row) = self.document.row(y) {
+                let points = self.wrap_points(row);
+                let start = points.get(line).copied().unwrap_or(0);
+                let end = points.get(line.saturating_add(1)).copied().unwrap_or_else(|| row.len());
+                let row = row.render(
+                    row.x_to_column(start, tab_width),
+                    row.x_to_column(end, tab_width),
+                    tab_width,
+                    &self.selection_in_row(y),
+                    &self.cursors_in_row(y),
+                );
+                println!("{row}\r");
+
+                line = line.saturating_add(1);
+                if line >= points.len() {
+                    y = y.saturating_add(1);
+                    line = 0;
+                }
+            } else if terminal_row == height / 3 && self.document.is_empty() {
+                self.draw_welcome_message();
+            } else {
+                println!("~\r");
+            }
+            terminal_row += 1;
+        }
+    }
+
+    // Ordered start and end of the selection
+    fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.selection_anchor.clone()?;
+        let cursor = self.cursor_position.clone();
+        let (start, mut end) = if (anchor.y, anchor.x) <= (cursor.y, cursor.x) {
+            (anchor, cursor)
+        } else {
+            (cursor, anchor)
+        };
+        // Visual mode takes the character under the cursor too
+        if self.config.modal && self.vi.mode == Mode::Visual {
+            end.x = end.x.saturating_add(1);
+        }
+        Some((start, end))
+    }
+
+    fn selection_in_row(&self, y: usize) -> Range<usize> {
+        match self.selection() {
+            Some((start, end)) if (start.y..=end.y).contains(&y) => {
+                let from = if y == start.y { start.x } else { 0 };
+                let to = if y == end.y { end.x } else { usize::MAX };
+                from..to
+            },
+            _ => 0..0,
+        }
+    }
+
+    fn cursors_in_row(&self, y: usize) -> Vec<usize> {
+        self.cursors.iter().filter(|cursor| cursor.y == y).map(|cursor| cursor.x).collect()
+    }
+
+    // Rows touched by the selection, or the cursor row. A selection ending at
+    // the start of a row doesn't include that row
+    fn selected_rows(&self) -> Range<usize> {
+        match self.selection() {
+            Some((start, end)) if end.y > start.y && end.x == 0 => start.y..end.y,
+            Some((start, end)) => start.y..end.y.saturating_add(1),
+            None => self.cursor_position.y..self.cursor_position.y.saturating_add(1),
+        }
+    }
+
+    fn wrap_points(&self, row: &Row) -> Vec<usize> {
+        row.wrap_points(
+            self.terminal.size().width as usize,
+            self.document.indentation().tab_width,
+            self.config.wrap_at_words,
+        )
+    }
+
+    // Screen line of the cursor within its row
+    fn cursor_line(&self) -> usize {
+        self.document.row(self.cursor_position.y).map_or(0, |row| {
+            self.wrap_points(row)
+                .iter()
+                .rposition(|point| *point <= self.cursor_position.x)
+                .unwrap_or(0)
+        })
+    }
+
+    fn screen_lines(&self, y: usize) -> usize {
+        self.document.row(y).map_or(1, |row| self.wrap_points(row).len())
+    }
+
+    fn wrapped_cursor_position(&self) -> Position {
+        let mut screen_y = 0_usize;
+        for y in self.offset.y..self.cursor_position.y {
+            screen_y = screen_y.saturating_add(self.screen_lines(y));
+        }
+        let screen_y = screen_y
+            .saturating_add(self.cursor_line())
+            .saturating_sub(self.wrap_offset);
+
+        let tab_width = self.document.indentation().tab_width;
+        let screen_x = self.document.row(self.cursor_position.y).map_or(0, |row| {
+            let line_start = self.wrap_points(row).get(self.cursor_line()).copied().unwrap_or(0);
+            row.x_to_column(self.cursor_position.x, tab_width)
+                .saturating_sub(row.x_to_column(line_start, tab_width))
+        });
+        Position {
+            x: std::cmp::min(screen_x, (self.terminal.size().width as usize).saturating_sub(1)),
+            y: screen_y,
+        }
+    }
+
     fn draw_welcome_message(&self) {
         let mut welcome_message = format!("Hecto editor -- version {VERSION}");
         let width = self.terminal.size().width as usize;
@@ -152,9 +438,7 @@ impl Editor {
 
     fn draw_status_bar(&self) {
         let filename = if let Some(filename) = &self.document.filename {
-            let mut filename = filename.clone();
-            filename.truncate(STATUS_BAR_LENGTH);
-            filename
+            truncate_to_width(filename, STATUS_BAR_LENGTH)
         } else {
             "[No name]".to_owned()
         };
@@ -164,15 +448,41 @@ impl Editor {
         } else {
             ""
         };
+        let read_only_indicator = if self.document.is_read_only() {
+            " [RO]"
+        } else {
+            ""
+        };
+        let recording_indicator = self
+            .macros
+            .recording()
+            .map_or_else(String::new, |register| format!(" [recording {register}]"));
+        let cursors_indicator = if self.cursors.is_empty() {
+            String::new()
+        } else {
+            format!(" [{} cursors]", self.cursors.len().saturating_add(1))
+        };
 
 
+        let mode_indicator = if self.config.modal {
+            format!("-- {} -- ", self.vi.mode.name())
+        } else {
+            String::new()
+        };
+
         let len = self.document.len();
-        let status = format!("{filename} - {len} lines{modified_indicator}");
-        let line_indicator = format!("{} | {}/{}", 
+        let status = format!("{mode_indicator}{filename} - {len} lines{modified_indicator}{read_only_indicator}{cursors_indicator}{recording_indicator}");
+        let line_indicator = format!("{} | {} | {} | {}/{}", 
             self.document.file_type(),
+            self.document.encoding(),
+            self.document.indentation().name(),
             self.cursor_position.y.saturating_add(1),
             len);
-        let spaces = " ".repeat(self.terminal.size().width as usize - status.len() - line_indicator.len());
+        let spaces = " ".repeat(
+            (self.terminal.size().width as usize)
+                .saturating_sub(status.width())
+                .saturating_sub(line_indicator.width()),
+        );
 
         Terminal::set_bg_color(STATUS_BG_COLOR);
         Terminal::set_fg_color(STATUS_FG_COLOR);
@@ -186,254 +496,1827 @@ impl Editor {
 
         let message = &self.status_message;
         if Instant::now() - message.timestamp < Duration::new(5, 0) {
-            let mut text = message.text.clone();
-            text.truncate(self.terminal.size().width as usize);
+            let text = truncate_to_width(&message.text, self.terminal.size().width as usize);
             print!("{text}\r");
         }
     }
 
     fn process_keypress(&mut self) -> Result<(), std::io::Error> {
-        let pressed_key = Terminal::read_key()?;
-        match pressed_key {
-            Key::Ctrl('q') => {
-                if self.quit_times > 0 && self.document.is_dirty() {
-                    self.status_message = StatusMessage::from(format!(
-                        "WARNING! File has unsaved changes. Press Ctrl-Q {} more times to exit without saving",
-                        self.quit_times
-                    ));
-                    self.quit_times -= 1;
+        if !self.keymap.is_pending() {
+            self.macros.mark();
+        }
+        let pressed_key = match self.macros.next_key() {
+            Some(key) => key,
+            None => match self.terminal.read_event(Some(IDLE_INTERVAL))? {
+                None => return Ok(()),
+                Some(Event::Key(key)) => {
+                    self.macros.record(key);
+                    key
+                },
+                Some(Event::Mouse(event)) => {
+                    self.process_mouse(event);
                     return Ok(());
+                },
+                Some(Event::Unsupported(_)) => return Ok(()),
+            },
+        };
+        self.process_key(pressed_key);
+        Ok(())
+    }
+
+    // Macros don't record mouse events
+    fn process_mouse(&mut self, event: MouseEvent) {
+        let height = self.terminal.size().height as usize;
+        match event {
+            MouseEvent::Press(MouseButton::Left, column, line) => {
+                let line = usize::from(line).saturating_sub(1);
+                if line >= height {
+                    return;
                 }
-                self.should_quit = true;
+                let position = self.screen_to_position(usize::from(column).saturating_sub(1), line);
+                self.selection_anchor = None;
+                self.cursors.clear();
+                if self.vi.mode == Mode::Visual {
+                    self.vi.mode = Mode::Normal;
+                }
+                self.cursor_position = position.clone();
+                self.drag_start = Some(position);
             },
-            Key::Alt('s') => self.save(true),
-            Key::Ctrl('s') => self.save(false),
-            Key::Ctrl('f') => self.search(),
-            Key::Up | Key::Down | Key::Left | Key::Right 
-            | Key::PageUp | Key::PageDown | Key::Home | Key::End => self.move_cursor(pressed_key),
-            
-            Key::Delete => self.document.delete(&self.cursor_position),
-            Key::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0{
-                    self.move_cursor(Key::Left);
-                    self.document.delete(&self.cursor_position);
+            MouseEvent::Hold(column, line) => {
+                let Some(start) = self.drag_start.clone() else {
+                    return;
+                };
+                let line = std::cmp::min(usize::from(line).saturating_sub(1), height.saturating_sub(1));
+                self.cursor_position = self.screen_to_position(usize::from(column).saturating_sub(1), line);
+                if self.selection_anchor.is_none() {
+                    self.selection_anchor = Some(start);
                 }
-            }
-            Key::Char(c) => {
-                self.document.insert(&self.cursor_position, c);
-                self.move_cursor(Key::Right);
             },
-            _ => (),
+            MouseEvent::Release(..) => self.drag_start = None,
+            MouseEvent::Press(MouseButton::WheelUp, ..) => self.scroll_view(true),
+            MouseEvent::Press(MouseButton::WheelDown, ..) => self.scroll_view(false),
+            MouseEvent::Press(..) => return,
+        }
 
+        // Outside insert mode the cursor stays on a character
+        if self.config.modal && self.vi.mode != Mode::Insert {
+            let len = self.document.row(self.cursor_position.y).map_or(0, Row::len);
+            self.cursor_position.x = std::cmp::min(self.cursor_position.x, len.saturating_sub(1));
         }
+        self.after_keypress();
+    }
 
-        self.scroll();
-        if self.quit_times < QUIT_TIMES {
-            self.quit_times = QUIT_TIMES;
-            self.status_message = StatusMessage::from(String::new());
+    // The document position shown at a screen cell of the text area, the end
+    // of the row past it
+    fn screen_to_position(&self, column: usize, line: usize) -> Position {
+        let tab_width = self.document.indentation().tab_width;
+        let last_row = self.document.len().saturating_sub(1);
+        if !self.config.soft_wrap {
+            let y = std::cmp::min(self.offset.y.saturating_add(line), last_row);
+            let x = self.document.row(y).map_or(0, |row| row.column_to_x(self.offset.x.saturating_add(column), tab_width));
+            return Position { x, y };
         }
 
-        Ok(())
+        let mut y = self.offset.y;
+        let mut row_line = self.wrap_offset;
+        for _ in 0..line {
+            if row_line.saturating_add(1) < self.screen_lines(y) {
+                row_line = row_line.saturating_add(1);
+            } else if y < last_row {
+                y = y.saturating_add(1);
+                row_line = 0;
+            }
+        }
+        let Some(row) = self.document.row(y) else {
+            return Position { x: 0, y };
+        };
+        let points = self.wrap_points(row);
+        let start = points.get(row_line).copied().unwrap_or(0);
+        let mut x = row.column_to_x(row.x_to_column(start, tab_width).saturating_add(column), tab_width);
+        // Stay on the clicked screen line rather than the start of the next one
+        if let Some(next_start) = points.get(row_line.saturating_add(1)) {
+            x = std::cmp::min(x, next_start.saturating_sub(1));
+        }
+        Position { x, y }
     }
 
-    fn scroll(&mut self) {
-        let Position { x, y } = self.cursor_position;
-        let width = self.terminal.size().width as usize;
+    // Scrolls the view without the cursor, unless it would leave the screen
+    fn scroll_view(&mut self, up: bool) {
         let height = self.terminal.size().height as usize;
-        let offset = &mut self.offset;
+        let last_row = self.document.len().saturating_sub(1);
+        self.offset.y = if up {
+            self.offset.y.saturating_sub(WHEEL_SCROLL_ROWS)
+        } else {
+            std::cmp::min(self.offset.y.saturating_add(WHEEL_SCROLL_ROWS), last_row)
+        };
+        self.wrap_offset = 0;
 
-        if y < offset.y {
-            offset.y = y;
-        } else if y >= offset.y.saturating_add(height) {
-            offset.y = y.saturating_sub(height).saturating_add(1);
+        // Last row entirely on screen
+        let mut bottom = self.offset.y.saturating_add(height.saturating_sub(1));
+        if self.config.soft_wrap {
+            bottom = self.offset.y;
+            let mut lines = self.screen_lines(bottom);
+            while bottom < last_row && lines.saturating_add(self.screen_lines(bottom.saturating_add(1))) <= height {
+                bottom = bottom.saturating_add(1);
+                lines = lines.saturating_add(self.screen_lines(bottom));
+            }
         }
-        
-        if x < offset.x {
-            offset.x = x;
-        } else if x >= offset.x.saturating_add(width) {
-            offset.x = x.saturating_sub(width).saturating_add(1);
+        let y = self.cursor_position.y.clamp(self.offset.y, std::cmp::max(bottom, self.offset.y));
+        if y != self.cursor_position.y {
+            self.cursor_position.y = y;
+            self.move_cursor(Key::Null);
         }
     }
 
-    fn move_cursor(&mut self, key: Key) {
-        let Position { mut x, mut y } = self.cursor_position;
-        let height = self.document.len();
-        let mut width = if let Some(row) = self.document.row(y) {
-            row.len()
-        } else {
-            0
-        };
+    // Keys of a playing macro come before the terminal, those typed while
+    // recording one are kept in it
+    fn read_key(&mut self) -> Result<Key, std::io::Error> {
+        if let Some(key) = self.macros.next_key() {
+            return Ok(key);
+        }
+        let key = self.terminal.read_key()?;
+        self.macros.record(key);
+        Ok(key)
+    }
 
-        match key {
-            Key::Up         => {
-                if y == 0 {
-                    x = 0;
-                } else {
-                    y = y.saturating_sub(1)
-                }
+    fn process_key(&mut self, pressed_key: Key) {
+        let changes = self.document.changes();
+        if self.config.modal && self.vi_keypress(pressed_key) {
+            // Only typing in insert mode happens at every cursor
+            if self.document.changes() != changes {
+                self.cursors.clear();
+            }
+            self.after_keypress();
+            return;
+        }
+        let action = match self.keymap.lookup(pressed_key) {
+            Lookup::Action(action) => Some(action),
+            Lookup::Pending(keys) => {
+                self.status_message = StatusMessage::from(format!("{} -", keymap::keys_name(&keys)));
+                return;
             },
-            Key::Down       => {
-                if y < height.saturating_sub(1) {
-                    y = y.saturating_add(1);
-                } else if let Some(row) = self.document.row(y) {
-                    x = row.len();
+            Lookup::Unbound(keys) if keys.len() > 1 => {
+                self.status_message = StatusMessage::from(format!("{} is not bound.", keymap::keys_name(&keys)));
+                return;
+            },
+            Lookup::Unbound(_) => None,
+        };
+        let edits = action.map_or(matches!(pressed_key, Key::Char(_)), Action::edits);
+        if self.document.is_read_only() && edits {
+            self.refuse_read_only();
+            return;
+        }
+
+        match action {
+            Some(Action::Quit) => {
+                if self.quit_times > 0 && self.document.is_dirty() {
+                    self.status_message = StatusMessage::from(format!(
+                        "WARNING! File has unsaved changes. Press {} {} more times to exit without saving",
+                        self.keymap.keys_for(Action::Quit),
+                        self.quit_times
+                    ));
+                    self.quit_times -= 1;
+                    return;
                 }
+                self.should_quit = true;
             },
-            Key::Left       => {
-                if x > 0 {
-                    x -= 1;
-                } else if y > 0 {
-                    y -= 1;
-                    if let Some(row) = self.document.row(y) {
-                        x = row.len();
-                    } else {
-                        x = 0;
+            Some(action) if !action.is_multi_cursor() => self.run_action(action),
+            _ => self.at_each_cursor(|editor| match action {
+                Some(action) => editor.run_action(action),
+                None => {
+                    if let Key::Char(c) = pressed_key {
+                        editor.insert_char(c);
                     }
+                },
+            }),
+        }
+
+        if matches!(action, Some(Action::Backspace | Action::Delete | Action::Newline))
+            || (action.is_none() && matches!(pressed_key, Key::Char(_)))
+        {
+            self.selection_anchor = None;
+        }
+        self.after_keypress();
+    }
+
+    fn run_action(&mut self, action: Action) {
+        // The other cursors can't follow edits made at one of them
+        if action.edits() && !action.is_multi_cursor() {
+            self.cursors.clear();
+        }
+        match action {
+            Action::Quit => self.should_quit = true,
+            Action::SaveAs => self.save(true),
+            Action::Save => self.save(false),
+            Action::Search => self.search(),
+            Action::ChangeEncoding => self.change_encoding(),
+            Action::ToggleReadOnly => self.toggle_read_only(),
+            Action::SaveWithSudo => {
+                self.save_with_sudo();
+            },
+            Action::ToggleSoftWrap => self.toggle_soft_wrap(),
+            Action::MatchingBracket => self.jump_to_matching_bracket(),
+            Action::ToggleSelection => self.toggle_selection(),
+            Action::ClearSelection => {
+                self.selection_anchor = None;
+                self.cursors.clear();
+            },
+            Action::ToggleComment => self.toggle_comment(),
+            Action::MoveUp => self.move_cursor(Key::Up),
+            Action::MoveDown => self.move_cursor(Key::Down),
+            Action::MoveLeft => self.move_cursor(Key::Left),
+            Action::MoveRight => self.move_cursor(Key::Right),
+            Action::PageUp => self.move_cursor(Key::PageUp),
+            Action::PageDown => self.move_cursor(Key::PageDown),
+            Action::LineStart => self.move_cursor(Key::Home),
+            Action::LineEnd => self.move_cursor(Key::End),
+            Action::Delete => self.document.delete(&self.cursor_position),
+            Action::Backspace => self.backspace(),
+            Action::Newline => self.insert_newline(),
+            Action::Tab if self.selection_anchor.is_some() => self.indent_selection(true),
+            Action::Tab if self.document.indentation().expand_tabs => {
+                // Spaces up to the next indent stop
+                let indent_width = self.document.indentation().indent_width;
+                let column = self.cursor_column();
+                for _ in 0..indent_width.saturating_sub(column % indent_width) {
+                    self.document.insert(&self.cursor_position, ' ');
+                    self.move_cursor(Key::Right);
                 }
             },
-            Key::Right      => {
-                if x < width {
-                    x += 1;
-                } else if y < height.saturating_sub(1) {
-                    y += 1;
-                    x = 0;
+            Action::Tab => self.insert_char('\t'),
+            Action::Outdent => self.indent_selection(false),
+            Action::DuplicateRows => self.duplicate_rows(),
+            Action::MoveRowsUp => self.move_rows(true),
+            Action::MoveRowsDown => self.move_rows(false),
+            Action::JoinRows => self.join_rows(),
+            Action::DeleteRows => self.delete_rows(),
+            Action::SortRows => self.sort_rows(),
+            Action::Undo => self.undo(true),
+            Action::Redo => self.undo(false),
+            Action::CommandLine => self.command_line(),
+            Action::AddCursorAtNext => self.add_cursor_at_next(),
+            Action::AddCursorAbove => self.add_cursor_vertically(true),
+            Action::AddCursorBelow => self.add_cursor_vertically(false),
+            Action::RecordMacro => self.record_macro(),
+            Action::PlayMacro => self.play_macro(),
+            Action::Script(index) => {
+                if let Some(function) = self.keymap.script(index).map(ToOwned::to_owned) {
+                    self.run_script(&function, Vec::new());
                 }
             },
-            Key::PageUp     => y = y.saturating_sub(self.terminal.size().height as usize),
-            Key::PageDown   => y = std::cmp::min(y.saturating_add(self.terminal.size().height as usize), height.saturating_sub(1)),
-            Key::Home       => x = 0,
-            Key::End        => x = width,
-            _ => {},
+            Action::SaveMacros => {
+                self.status_message = StatusMessage::from(match self.macros.save() {
+                    Ok(()) => "Macros saved.".to_owned(),
+                    Err(error) => format!("ERR: Could not save macros: {error}"),
+                });
+            },
         }
+    }
 
-        width = if let Some(row) = self.document.row(y) {
-            row.len()
+    // Runs the edit or movement at each cursor in document order, as one
+    // undoable change. The cursors after the one being edited keep the number
+    // of rows below them and their distance to the end of their row, which holds
+    // as long as the edit only touches text before them
+    fn at_each_cursor(&mut self, mut run: impl FnMut(&mut Self)) {
+        let mut cursors: Vec<(Position, bool)> = self.cursors.drain(..).map(|cursor| (cursor, false)).collect();
+        cursors.push((self.cursor_position.clone(), true));
+        cursors.sort_by_key(|(cursor, _)| (cursor.y, cursor.x));
+
+        self.document.begin_group();
+        for index in 0..cursors.len() {
+            let len = self.document.len();
+            let row_len = |document: &Document, y: usize| document.row(y).map_or(0, Row::len);
+            let distances: Vec<(usize, usize)> = cursors[index.saturating_add(1)..]
+                .iter()
+                .map(|(cursor, _)| (len.saturating_sub(cursor.y), row_len(&self.document, cursor.y).saturating_sub(cursor.x)))
+                .collect();
+
+            self.cursor_position = cursors[index].0.clone();
+            run(self);
+            cursors[index].0 = self.cursor_position.clone();
+
+            let len = self.document.len();
+            for ((cursor, _), (rows, x)) in cursors[index.saturating_add(1)..].iter_mut().zip(distances) {
+                cursor.y = len.saturating_sub(rows);
+                cursor.x = row_len(&self.document, cursor.y).saturating_sub(x);
+            }
+        }
+        self.document.end_group();
+
+        self.cursors = cursors.into_iter().map(|(cursor, primary)| {
+            if primary {
+                self.cursor_position = cursor.clone();
+            }
+            cursor
+        }).collect();
+        self.merge_cursors();
+    }
+
+    // Drops the cursors that ended up at the same place as another one
+    fn merge_cursors(&mut self) {
+        let last_row = self.document.len().saturating_sub(1);
+        for cursor in &mut self.cursors {
+            cursor.y = std::cmp::min(cursor.y, last_row);
+            cursor.x = std::cmp::min(cursor.x, self.document.row(cursor.y).map_or(0, Row::len));
+        }
+        let primary = self.cursor_position.clone();
+        self.cursors.retain(|cursor| *cursor != primary);
+        self.cursors.sort_by_key(|cursor| (cursor.y, cursor.x));
+        self.cursors.dedup();
+    }
+
+    // Adds a cursor at the next occurrence of the selected text, or of the word
+    // at the cursor, at the same place within it. The new cursor is the primary one
+    fn add_cursor_at_next(&mut self) {
+        let Position { x, y } = self.cursor_position.clone();
+        let (query, start, options) = if let Some((start, end)) = self.selection() {
+            if start.y != end.y || start.x == end.x {
+                self.status_message = StatusMessage::from("Select text within a row to add cursors at it.".to_owned());
+                return;
+            }
+            (self.document.text(&start, &end), start, SearchOptions::default())
         } else {
-            0
+            let Some(row) = self.document.row(y) else {
+                return;
+            };
+            let is_word = |x: usize| row.grapheme(x).is_some_and(|grapheme| grapheme.chars().any(|c| c.is_alphanumeric() || c == '_'));
+            let mut start = x;
+            while start > 0 && is_word(start.saturating_sub(1)) {
+                start -= 1;
+            }
+            let mut end = x;
+            while is_word(end) {
+                end = end.saturating_add(1);
+            }
+            if start == end {
+                self.status_message = StatusMessage::from("No word at the cursor.".to_owned());
+                return;
+            }
+            let start = Position { x: start, y };
+            let end = Position { x: end, y };
+            let options = SearchOptions { whole_word: true, ..SearchOptions::default() };
+            (self.document.text(&start, &end), start, options)
         };
-        if x > width {
-            x = width;
+
+        let after = Position { x: start.x.saturating_add(1), y };
+        let found = self
+            .document
+            .find(&query, &after, SearchDirection::Forward, &options)
+            .or_else(|| self.document.find(&query, &Position::default(), SearchDirection::Forward, &options));
+        let target = found.map(|found| Position { x: found.x.saturating_add(x.saturating_sub(start.x)), y: found.y });
+        match target {
+            Some(target) if target != self.cursor_position && !self.cursors.contains(&target) => {
+                self.cursors.push(std::mem::replace(&mut self.cursor_position, target));
+                self.selection_anchor = None;
+                self.merge_cursors();
+            },
+            _ => self.status_message = StatusMessage::from(format!("No more occurrences of {query}.")),
         }
+    }
 
-        self.cursor_position = Position { x, y };
+    // Adds a cursor on the row above the topmost cursor or below the bottommost
+    // one, in the same screen column. The new cursor is the primary one
+    fn add_cursor_vertically(&mut self, up: bool) {
+        let mut cursors = self.cursors.clone();
+        cursors.push(self.cursor_position.clone());
+        cursors.sort_by_key(|cursor| (cursor.y, cursor.x));
+        let from = if up { cursors.first() } else { cursors.last() };
+        let Some(from) = from.cloned() else {
+            return;
+        };
+
+        let primary = std::mem::replace(&mut self.cursor_position, from.clone());
+        self.move_cursor(if up { Key::Up } else { Key::Down });
+        if self.cursor_position.y == from.y {
+            self.cursor_position = primary;
+            return;
+        }
+        self.cursors = cursors;
+        self.selection_anchor = None;
+        self.merge_cursors();
     }
 
-    fn prompt<C>(&mut self, prompt: &str, default_value: &str, mut callback: C) -> Result<Option<String>, std::io::Error> 
-    where 
-        C: FnMut(&mut Self, Key, &String),
-    {
-        let mut result = default_value.to_owned();
+    fn record_macro(&mut self) {
+        if let Some((register, len)) = self.macros.stop() {
+            self.status_message = StatusMessage::from(format!("Recorded {len} keys to macro {register}."));
+            return;
+        }
+        self.status_message = StatusMessage::from("Register to record to (a-z):".to_owned());
+        let key = match self.refresh_screen().and_then(|()| self.read_key()) {
+            Ok(key) => key,
+            Err(error) => return self.exit_on_error(error),
+        };
+        match key {
+            Key::Char(register) if macros::is_register(register) => {
+                self.macros.start(register);
+                self.status_message = StatusMessage::from(format!(
+                    "Recording macro {register}, {} to stop.",
+                    self.keymap.keys_for(Action::RecordMacro)
+                ));
+            },
+            _ => self.status_message = StatusMessage::from("Not a register, recording cancelled.".to_owned()),
+        }
+    }
 
+    // A count typed before the register repeats the macro
+    fn play_macro(&mut self) {
+        let mut count: Option<usize> = None;
         loop {
-            self.status_message = StatusMessage::from(format!("{}{}", prompt, result));
-            self.refresh_screen()?;
-
-            let key = Terminal::read_key()?;
+            self.status_message = StatusMessage::from(format!(
+                "Macro to play (a-z), a count first repeats it: {}",
+                count.map_or_else(String::new, |count| count.to_string())
+            ));
+            let key = match self.refresh_screen().and_then(|()| self.read_key()) {
+                Ok(key) => key,
+                Err(error) => return self.exit_on_error(error),
+            };
             match key {
-                Key::Char('\n') => break,
-                Key::Char(c) => {
-                    if !c.is_control() {
-                        result.push(c);
-                    }
+                Key::Char(digit @ '0'..='9') => {
+                    let digit = digit.to_digit(10).unwrap_or(0) as usize;
+                    count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
                 },
-                Key::Backspace => {
-                    if !result.is_empty() {
-                        result.truncate(result.len() - 1);
+                Key::Char(register) if macros::is_register(register) => {
+                    self.status_message = StatusMessage::from(String::new());
+                    if let Err(error) = self.macros.play(register, count.unwrap_or(1)) {
+                        self.status_message = StatusMessage::from(error);
                     }
+                    return;
                 },
-                Key::Ctrl('c') | Key::Esc => {
-                    result.truncate(0);
-                    break;
-                },
-                Key::Ctrl('q') => {
-                    self.should_quit = true;
-                    result.truncate(0);
-                    break;
+                _ => {
+                    self.status_message = StatusMessage::from("Not a register.".to_owned());
+                    return;
                 },
-                _ => (),
             }
-            callback(self, key, &result);
-        };
-
-        self.status_message = StatusMessage::from(String::new());
-        if result.is_empty() {
-            return Ok(None);
         }
-        Ok(Some(result))
     }
 
-    fn save(&mut self, ask_filename: bool) {
-        if self.document.filename.is_none() || ask_filename {
-            let default_value = self.document.filename.as_ref().unwrap_or(&String::new()).clone();
-            let new_name = self.prompt(
-                "Save as: ",
-                &default_value[..],
-                |_, _, _| {}
-            ).unwrap_or(None);
-            if new_name.is_none() {
-                self.status_message = StatusMessage::from("Save aborted.".to_string());
-                return;
-            }
+    fn refuse_read_only(&mut self) {
+        self.status_message = StatusMessage::from(format!(
+            "File is read-only, {} to allow editing.",
+            self.keymap.keys_for(Action::ToggleReadOnly)
+        ));
+    }
 
-            self.document.filename = new_name;
-        }
+    fn after_keypress(&mut self) {
+        self.scroll();
+        self.highlight_matching_bracket();
+        if self.quit_times < QUIT_TIMES {
+            self.quit_times = QUIT_TIMES;
+            self.status_message = StatusMessage::from(String::new());
+        }
+    }
+
+    // Returns false for keys left to the modeless bindings
+    fn vi_keypress(&mut self, key: Key) -> bool {
+        if self.vi.mode == Mode::Insert {
+            self.vi.record(key);
+            if key != Key::Esc {
+                return false;
+            }
+            self.vi.end_change();
+            self.vi.mode = Mode::Normal;
+            self.cursor_position.x = self.cursor_position.x.saturating_sub(1);
+            return true;
+        }
+
+        let c = match key {
+            Key::Char(c) => c,
+            Key::Backspace => 'h',
+            Key::Delete => 'x',
+            Key::Ctrl('r') => {
+                self.undo(false);
+                return true;
+            },
+            Key::Esc => {
+                self.vi.reset();
+                self.vi.mode = Mode::Normal;
+                self.selection_anchor = None;
+                return true;
+            },
+            _ => return false,
+        };
+        if let Some((command, count)) = self.vi.parse(c) {
+            self.vi_command(command, count);
+        }
+
+        // Outside insert mode the cursor stays on a character
+        if self.vi.mode != Mode::Insert {
+            let len = self.document.row(self.cursor_position.y).map_or(0, Row::len);
+            if self.cursor_position.x >= len {
+                self.cursor_position.x = len.saturating_sub(1);
+            }
+        }
+        true
+    }
+
+    fn vi_command(&mut self, command: Command, count: Option<usize>) {
+        let changes = match command {
+            Command::Operate(operator, _) | Command::OperateRows(operator) => operator != 'y',
+            Command::Insert(_) | Command::DeleteChars | Command::Paste(_) => true,
+            _ => false,
+        };
+        if changes && self.document.is_read_only() {
+            self.refuse_read_only();
+            return;
+        }
+        if changes {
+            self.vi.begin_change();
+        }
+
+        let times = count.unwrap_or(1);
+        match command {
+            Command::Move(motion) => self.vi_move(motion, count),
+            Command::Operate(operator, motion) => self.vi_operate(operator, motion, count),
+            Command::OperateRows(operator) => {
+                let y = self.cursor_position.y;
+                self.vi_operate_rows(operator, y..y.saturating_add(times));
+            },
+            Command::OperateSelection(operator) => {
+                if let Some((start, end)) = self.selection() {
+                    self.selection_anchor = None;
+                    self.vi.mode = Mode::Normal;
+                    self.vi_apply(operator, &start, &end);
+                }
+            },
+            Command::Insert(c) => self.vi_insert(c),
+            Command::DeleteChars => {
+                let start = self.cursor_position.clone();
+                let end = vi::target(&self.document, &start, Motion::Right, count);
+                self.vi_apply('d', &start, &end);
+            },
+            Command::Paste(after) => self.vi_paste(after, times),
+            Command::Undo => self.undo(true),
+            Command::Visual => {
+                if self.vi.mode == Mode::Visual {
+                    self.vi.mode = Mode::Normal;
+                    self.selection_anchor = None;
+                } else {
+                    self.vi.mode = Mode::Visual;
+                    self.selection_anchor = Some(self.cursor_position.clone());
+                }
+            },
+            Command::Repeat => self.vi_repeat(),
+            Command::Ex => self.command_line(),
+        }
+
+        // Changes that enter insert mode end with Esc
+        if changes && self.vi.mode != Mode::Insert {
+            self.vi.end_change();
+        }
+    }
+
+    fn vi_move(&mut self, motion: Motion, count: Option<usize>) {
+        let key = match motion {
+            Motion::Up => Key::Up,
+            Motion::Down => Key::Down,
+            _ => {
+                self.cursor_position = vi::target(&self.document, &self.cursor_position, motion, count);
+                return;
+            },
+        };
+        // Through `move_cursor` to keep the screen column
+        for _ in 0..count.unwrap_or(1) {
+            let y = self.cursor_position.y;
+            if (key == Key::Up && y == 0) || (key == Key::Down && y.saturating_add(1) >= self.document.len()) {
+                break;
+            }
+            self.move_cursor(key);
+        }
+    }
+
+    fn vi_operate(&mut self, operator: char, motion: Motion, count: Option<usize>) {
+        let from = self.cursor_position.clone();
+        if motion.is_linewise() {
+            let to = vi::target(&self.document, &from, motion, count);
+            let rows = std::cmp::min(from.y, to.y)..std::cmp::max(from.y, to.y).saturating_add(1);
+            self.vi_operate_rows(operator, rows);
+            return;
+        }
+
+        // `cw` on a word changes up to its end, like `ce`
+        let on_word = self.chars_around_cursor().1.is_some_and(|c| !c.is_whitespace());
+        let motion = if operator == 'c' && motion == Motion::WordStart && on_word {
+            Motion::WordEnd
+        } else {
+            motion
+        };
+        let mut to = vi::target(&self.document, &from, motion, count);
+        // A word motion landing at the start of a later row stops at the end of the row before
+        if motion == Motion::WordStart && to.y > from.y {
+            let indent = self.document.row(to.y).map_or(0, |row| row.leading_whitespace().len());
+            if to.x <= indent {
+                to.y -= 1;
+                to.x = self.document.row(to.y).map_or(0, Row::len);
+            }
+        }
+
+        let (start, mut end) = if (from.y, from.x) <= (to.y, to.x) {
+            (from, to)
+        } else {
+            (to, from)
+        };
+        if motion.is_inclusive() {
+            end.x = end.x.saturating_add(1);
+        }
+        self.vi_apply(operator, &start, &end);
+    }
+
+    // Runs `d`, `c` or `y` on the text from `start` up to `end`
+    fn vi_apply(&mut self, operator: char, start: &Position, end: &Position) {
+        self.vi.register = self.document.text(start, end);
+        self.vi.linewise = false;
+        if operator != 'y' {
+            self.document.delete_range(start, end);
+        }
+        self.cursor_position = start.clone();
+        if operator == 'c' {
+            self.vi.mode = Mode::Insert;
+        }
+    }
+
+    fn vi_operate_rows(&mut self, operator: char, rows: Range<usize>) {
+        let rows = rows.start..std::cmp::min(rows.end, self.document.len());
+        if rows.is_empty() {
+            return;
+        }
+        let last = rows.end.saturating_sub(1);
+        let end = Position {
+            x: self.document.row(last).map_or(0, Row::len),
+            y: last,
+        };
+        self.vi.register = self.document.text(&Position { x: 0, y: rows.start }, &end);
+        self.vi.linewise = true;
+
+        match operator {
+            'd' => {
+                self.document.delete_rows(rows.clone());
+                self.cursor_position = Position {
+                    x: 0,
+                    y: std::cmp::min(rows.start, self.document.len().saturating_sub(1)),
+                };
+            },
+            'c' => {
+                // The indentation of the first row stays
+                let indent = self.document.row(rows.start).map_or(0, |row| row.leading_whitespace().len());
+                let start = Position { x: indent, y: rows.start };
+                self.document.delete_range(&start, &end);
+                self.cursor_position = start;
+                self.vi.mode = Mode::Insert;
+            },
+            _ => self.cursor_position.y = rows.start,
+        }
+    }
+
+    fn vi_insert(&mut self, c: char) {
+        let Position { x, y } = self.cursor_position;
+        let len = self.document.row(y).map_or(0, Row::len);
+        match c {
+            'a' => self.cursor_position.x = std::cmp::min(x.saturating_add(1), len),
+            'A' => self.cursor_position.x = len,
+            'I' => self.cursor_position.x = self.document.row(y).map_or(0, |row| row.leading_whitespace().len()),
+            'o' => {
+                let x = self.document.insert_newline(&Position { x: len, y }, self.config.auto_indent);
+                self.cursor_position = Position { x, y: y.saturating_add(1) };
+            },
+            'O' => {
+                let indent = if self.config.auto_indent {
+                    self.document.row(y).map_or("", Row::leading_whitespace).to_owned()
+                } else {
+                    String::new()
+                };
+                self.document.insert_text(&Position { x: 0, y }, &format!("{indent}\n"));
+                self.cursor_position = Position { x: indent.len(), y };
+            },
+            _ => (),
+        }
+        self.vi.mode = Mode::Insert;
+    }
+
+    fn vi_paste(&mut self, after: bool, times: usize) {
+        if self.vi.register.is_empty() {
+            return;
+        }
+        let Position { x, y } = self.cursor_position;
+        if self.vi.linewise {
+            let text = vec![self.vi.register.as_str(); times].join("\n");
+            let y = if after { std::cmp::min(y.saturating_add(1), self.document.len()) } else { y };
+            if y >= self.document.len() && !self.document.is_empty() {
+                let last = self.document.len().saturating_sub(1);
+                let x = self.document.row(last).map_or(0, Row::len);
+                self.document.insert_text(&Position { x, y: last }, &format!("\n{text}"));
+            } else {
+                self.document.insert_text(&Position { x: 0, y }, &format!("{text}\n"));
+            }
+            self.cursor_position = Position { x: 0, y };
+        } else {
+            let len = self.document.row(y).map_or(0, Row::len);
+            let at = Position {
+                x: if after { std::cmp::min(x.saturating_add(1), len) } else { x },
+                y,
+            };
+            let end = self.document.insert_text(&at, &self.vi.register.repeat(times));
+            self.cursor_position = Position { x: end.x.saturating_sub(1), y: end.y };
+        }
+    }
+
+    fn vi_repeat(&mut self) {
+        let keys = self.vi.last_change();
+        self.vi.repeating = true;
+        for key in keys {
+            self.process_key(key);
+        }
+        self.vi.repeating = false;
+    }
+
+    // The bracket under the cursor, or right before it
+    fn bracket_pair(&self, max_rows: usize) -> Option<(Position, Position)> {
+        if let Some(matching) = self.document.matching_bracket(&self.cursor_position, max_rows) {
+            return Some((self.cursor_position.clone(), matching));
+        }
+        let Position { x, y } = self.cursor_position;
+        let before = Position { x: x.checked_sub(1)?, y };
+        let matching = self.document.matching_bracket(&before, max_rows)?;
+        Some((before, matching))
+    }
+
+    // Runs after every key, an unmatched bracket mustn't search the whole file
+    fn highlight_matching_bracket(&mut self) {
+        let max_rows = (self.terminal.size().height as usize).saturating_add(BRACKET_SEARCH_ROWS);
+        match self.bracket_pair(max_rows) {
+            Some((bracket, matching)) => self.document.highlight_brackets(&[bracket, matching]),
+            None => self.document.highlight_brackets(&[]),
+        }
+    }
+
+    fn jump_to_matching_bracket(&mut self) {
+        if let Some((_, matching)) = self.bracket_pair(usize::MAX) {
+            self.cursor_position = matching;
+        } else {
+            self.status_message = StatusMessage::from("No matching bracket.".to_owned());
+        }
+    }
+
+    // Characters right before and at the cursor
+    fn chars_around_cursor(&self) -> (Option<char>, Option<char>) {
+        let Position { x, y } = self.cursor_position;
+        let Some(row) = self.document.row(y) else {
+            return (None, None);
+        };
+        let before = x.checked_sub(1).and_then(|x| row.grapheme(x)).and_then(|g| g.chars().next());
+        let at = row.grapheme(x).and_then(|g| g.chars().next());
+        (before, at)
+    }
+
+    // Returns true when the key was fully handled by typing over a closer or inserting a pair
+    fn insert_pair(&mut self, c: char) -> bool {
+        if !self.config.auto_pairs {
+            return false;
+        }
+        let (before, at) = self.chars_around_cursor();
+        let pairs = self.document.auto_pairs();
+
+        if at == Some(c) && pairs.iter().any(|(_, close)| *close == c) {
+            self.move_cursor(Key::Right);
+            return true;
+        }
+
+        let Some((open, close)) = pairs.iter().find(|(open, _)| *open == c).copied() else {
+            return false;
+        };
+        let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+        // Quotes are apostrophes or suffixes inside words, and nothing pairs right before a word
+        if is_word(at) || (open == close && is_word(before)) {
+            return false;
+        }
+        self.document.insert(&self.cursor_position, open);
+        self.move_cursor(Key::Right);
+        self.document.insert(&self.cursor_position, close);
+        true
+    }
+
+    fn insert_char(&mut self, c: char) {
+        if self.insert_pair(c) {
+            return;
+        }
+        let Position { x, y } = self.cursor_position;
+        let blank = self.document.row(y).is_some_and(|row| x > 0 && row.as_str().trim().is_empty());
+        // A closing brace typed on an indentation-only row goes back one level
+        if self.config.auto_indent && blank && self.document.closes_block(c) {
+            let removed = self.document.outdent_rows(y..y.saturating_add(1)).first().copied().unwrap_or(0);
+            self.cursor_position.x = x.saturating_sub(removed);
+        }
+        self.document.insert(&self.cursor_position, c);
+        self.move_cursor(Key::Right);
+    }
+
+    fn insert_newline(&mut self) {
+        let x = self.document.insert_newline(&self.cursor_position, self.config.auto_indent);
+        if !self.document.is_read_only() {
+            self.cursor_position = Position {
+                x,
+                y: self.cursor_position.y.saturating_add(1),
+            };
+        }
+    }
+
+    fn backspace(&mut self) {
+        let Position { x, y } = self.cursor_position;
+        if x == 0 && y == 0 {
+            return;
+        }
+
+        // Within space indentation, Backspace removes up to the previous indent stop
+        let indentation = self.document.indentation();
+        let spaces = self.document.row(y).map_or(0, |row| {
+            row.as_str().len().saturating_sub(row.as_str().trim_start_matches(' ').len())
+        });
+        let count = if self.config.auto_indent && indentation.expand_tabs && x > 0 && x <= spaces {
+            (x.saturating_sub(1) % indentation.indent_width).saturating_add(1)
+        } else {
+            1
+        };
+
+        // Backspace inside an empty pair removes both halves
+        if self.config.auto_pairs {
+            if let (Some(before), Some(at)) = self.chars_around_cursor() {
+                if self.document.auto_pairs().contains(&(before, at)) {
+                    self.document.delete(&self.cursor_position);
+                }
+            }
+        }
+        for _ in 0..count {
+            self.move_cursor(Key::Left);
+            self.document.delete(&self.cursor_position);
+        }
+    }
 
-        if self.document.save().is_ok() {
-            self.status_message = StatusMessage::from("File saved successfully.".to_string());
+    fn cursor_column(&self) -> usize {
+        self.document.row(self.cursor_position.y).map_or(0, |row| {
+            row.x_to_column(self.cursor_position.x, self.document.indentation().tab_width)
+        })
+    }
+
+    fn scroll_wrapped(&mut self) {
+        let height = self.terminal.size().height as usize;
+        let cursor_line = self.cursor_line();
+        self.offset.x = 0;
+        self.wrap_offset = std::cmp::min(self.wrap_offset, self.screen_lines(self.offset.y).saturating_sub(1));
+
+        if (self.cursor_position.y, cursor_line) < (self.offset.y, self.wrap_offset) {
+            self.offset.y = self.cursor_position.y;
+            self.wrap_offset = cursor_line;
+            return;
+        }
+
+        // Walk back from the cursor to find the first screen line to show
+        let mut y = self.cursor_position.y;
+        let mut line = cursor_line;
+        for _ in 1..height {
+            if (y, line) <= (self.offset.y, self.wrap_offset) {
+                return;
+            }
+            if line > 0 {
+                line -= 1;
+            } else {
+                y = y.saturating_sub(1);
+                line = self.screen_lines(y).saturating_sub(1);
+            }
+        }
+        if (y, line) > (self.offset.y, self.wrap_offset) {
+            self.offset.y = y;
+            self.wrap_offset = line;
+        }
+    }
+
+    fn scroll(&mut self) {
+        if self.config.soft_wrap {
+            self.scroll_wrapped();
+            return;
+        }
+
+        let y = self.cursor_position.y;
+        let x = self.cursor_column();
+        // A wide grapheme under the cursor has to fit on screen entirely
+        let x_end = self.document.row(y).map_or(0, |row| {
+            row.x_to_column(self.cursor_position.x.saturating_add(1), self.document.indentation().tab_width)
+        }).max(x.saturating_add(1));
+        let width = self.terminal.size().width as usize;
+        let height = self.terminal.size().height as usize;
+        let offset = &mut self.offset;
+
+        if y < offset.y {
+            offset.y = y;
+        } else if y >= offset.y.saturating_add(height) {
+            offset.y = y.saturating_sub(height).saturating_add(1);
+        }
+        
+        if x < offset.x {
+            offset.x = x;
+        } else if x_end > offset.x.saturating_add(width) {
+            offset.x = x_end.saturating_sub(width);
+        }
+    }
+
+    // Up and Down follow screen lines when soft wrapping
+    fn move_cursor_wrapped(&mut self, key: Key) -> bool {
+        let Position { x, y } = self.cursor_position;
+        let tab_width = self.document.indentation().tab_width;
+        let Some(row) = self.document.row(y) else {
+            return false;
+        };
+        let points = self.wrap_points(row);
+        let line = self.cursor_line();
+        let column = row.x_to_column(x, tab_width)
+            .saturating_sub(row.x_to_column(points.get(line).copied().unwrap_or(0), tab_width));
+
+        let (target_y, target_line) = match key {
+            Key::Up if line > 0 => (y, line - 1),
+            Key::Up if y > 0 => (y - 1, self.screen_lines(y - 1).saturating_sub(1)),
+            Key::Down if line.saturating_add(1) < points.len() => (y, line.saturating_add(1)),
+            Key::Down if y.saturating_add(1) < self.document.len() => (y.saturating_add(1), 0),
+            _ => return false,
+        };
+
+        if let Some(target_row) = self.document.row(target_y) {
+            let target_points = self.wrap_points(target_row);
+            let start = target_points.get(target_line).copied().unwrap_or(0);
+            let mut target_x = target_row.column_to_x(
+                target_row.x_to_column(start, tab_width).saturating_add(column),
+                tab_width,
+            );
+            // Stay on the target screen line rather than the start of the next one
+            if let Some(next_start) = target_points.get(target_line.saturating_add(1)) {
+                target_x = std::cmp::min(target_x, next_start.saturating_sub(1));
+            }
+            self.cursor_position = Position { x: target_x, y: target_y };
+        }
+        true
+    }
+
+    fn move_cursor(&mut self, key: Key) {
+        if self.config.soft_wrap && matches!(key, Key::Up | Key::Down) && self.move_cursor_wrapped(key) {
+            return;
+        }
+
+        let Position { mut x, mut y } = self.cursor_position;
+        let height = self.document.len();
+        let mut width = if let Some(row) = self.document.row(y) {
+            row.len()
         } else {
-            self.status_message = StatusMessage::from("Error writting file!".to_string());
+            0
+        };
+
+        match key {
+            Key::Up         => {
+                if y == 0 {
+                    x = 0;
+                } else {
+                    y = y.saturating_sub(1)
+                }
+            },
+            Key::Down       => {
+                if y < height.saturating_sub(1) {
+                    y = y.saturating_add(1);
+                } else if let Some(row) = self.document.row(y) {
+                    x = row.len();
+                }
+            },
+            Key::Left       => {
+                if x > 0 {
+                    x -= 1;
+                } else if y > 0 {
+                    y -= 1;
+                    if let Some(row) = self.document.row(y) {
+                        x = row.len();
+                    } else {
+                        x = 0;
+                    }
+                }
+            },
+            Key::Right      => {
+                if x < width {
+                    x += 1;
+                } else if y < height.saturating_sub(1) {
+                    y += 1;
+                    x = 0;
+                }
+            },
+            Key::PageUp     => y = y.saturating_sub(self.terminal.size().height as usize),
+            Key::PageDown   => y = std::cmp::min(y.saturating_add(self.terminal.size().height as usize), height.saturating_sub(1)),
+            Key::Home       => x = 0,
+            Key::End        => x = width,
+            _ => {},
+        }
+
+        y = std::cmp::min(y, height);
+        if y != self.cursor_position.y {
+            // Moving vertically keeps the screen column, whatever tabs the rows hold
+            let tab_width = self.document.indentation().tab_width;
+            if let (Some(row), Some(new_row)) = (self.document.row(self.cursor_position.y), self.document.row(y)) {
+                if x == self.cursor_position.x {
+                    x = new_row.column_to_x(row.x_to_column(x, tab_width), tab_width);
+                }
+            }
+        }
+        width = if let Some(row) = self.document.row(y) {
+            row.len()
+        } else {
+            0
+        };
+        if x > width {
+            x = width;
+        }
+
+        self.cursor_position = Position { x, y };
+    }
+
+    fn prompt<C>(&mut self, prompt: &str, default_value: &str, mut callback: C) -> Result<Option<String>, std::io::Error> 
+    where 
+        C: FnMut(&mut Self, Key, &mut String),
+    {
+        let mut line = Prompt::default();
+        line.set_text(default_value);
+        // Each prompt recalls its own earlier answers
+        let history = self.prompt_history.get(prompt).cloned().unwrap_or_default();
+        let mut history_index = history.len();
+        let mut draft = String::new();
+        let mut cancelled = false;
+
+        loop {
+            let text = format!("{}{}{}{}", self.prompt_flags, prompt, line.text(), self.prompt_info);
+            let column = self.prompt_flags.width().saturating_add(prompt.width()).saturating_add(line.column());
+            let (text, column) = scroll_to_column(&text, column, self.terminal.size().width as usize);
+            self.status_message = StatusMessage::from(text);
+            self.prompt_cursor = Some(column);
+            self.refresh_screen()?;
+
+            let key = self.read_key()?;
+            match key {
+                Key::Char('\n') => break,
+                Key::Ctrl('c') | Key::Esc => {
+                    cancelled = true;
+                    break;
+                },
+                Key::Ctrl('q') => {
+                    self.should_quit = true;
+                    cancelled = true;
+                    break;
+                },
+                Key::Up if history_index > 0 => {
+                    if history_index == history.len() {
+                        draft = line.text().to_owned();
+                    }
+                    history_index -= 1;
+                    line.set_text(&history[history_index]);
+                },
+                Key::Down if history_index < history.len() => {
+                    history_index = history_index.saturating_add(1);
+                    line.set_text(history.get(history_index).unwrap_or(&draft));
+                },
+                _ => {
+                    line.handle(key);
+                },
+            }
+
+            let mut text = line.text().to_owned();
+            callback(self, key, &mut text);
+            if text != line.text() {
+                line.set_text(&text);
+            }
+        };
+
+        self.prompt_cursor = None;
+        self.status_message = StatusMessage::from(String::new());
+        let result = line.text().to_owned();
+        if cancelled || result.is_empty() {
+            return Ok(None);
+        }
+        if !result.trim().is_empty() {
+            let history = self.prompt_history.entry(prompt.to_owned()).or_default();
+            history.retain(|previous| *previous != result);
+            history.push(result.clone());
+            if history.len() > PROMPT_HISTORY_LENGTH {
+                history.remove(0);
+            }
+        }
+        Ok(Some(result))
+    }
+
+    fn save(&mut self, ask_filename: bool) {
+        if self.document.filename.is_none() || ask_filename {
+            let default_value = self.document.filename.as_ref().unwrap_or(&String::new()).clone();
+            let new_name = self.prompt(
+                "Save as: ",
+                &default_value[..],
+                |_, _, _| {}
+            ).unwrap_or(None);
+            if let Some(new_name) = new_name {
+                self.save_as(new_name);
+            } else {
+                self.status_message = StatusMessage::from("Save aborted.".to_string());
+            }
+            return;
+        } else if self.document.changed_on_disk() {
+            let answer = self
+                .prompt("File changed on disk since it was opened. Overwrite it? (y/n) ", "", |_, _, _| {})
+                .unwrap_or(None);
+            if answer.as_deref() != Some("y") {
+                self.status_message = StatusMessage::from("Save aborted.".to_string());
+                return;
+            }
+        }
+        self.write_file();
+    }
+
+    // The recovery file of the old name goes once saved, and the old name
+    // comes back if saving fails
+    fn save_as(&mut self, filename: String) {
+        let old_recovery = self.recovery();
+        let renamed = self.document.rename(filename);
+        if self.write_file() {
+            if let Some(recovery) = old_recovery {
+                recovery.remove();
+            }
+        } else {
+            self.document.undo_rename(renamed);
+        }
+    }
+
+    // Returns whether the file was written
+    fn write_file(&mut self) -> bool {
+        match self.document.save(self.config.backup) {
+            Ok(()) => {
+                self.remove_recovery();
+                self.status_message = StatusMessage::from("File saved successfully.".to_string());
+                true
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => {
+                let answer = self
+                    .prompt(&format!("Permission denied. Save with `{}`? (y/n) ", self.config.sudo_command), "", |_, _, _| {})
+                    .unwrap_or(None);
+                if answer.as_deref() == Some("y") {
+                    self.save_with_sudo()
+                } else {
+                    self.status_message = StatusMessage::from(format!("ERR: Could not save file: {error}"));
+                    false
+                }
+            },
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!(
+                    "ERR: Could not save file ({:?}): {error}",
+                    error.kind()
+                ));
+                false
+            },
+        }
+    }
+
+    // Returns whether the file was written
+    fn save_with_sudo(&mut self) -> bool {
+        if self.document.filename.is_none() {
+            self.status_message = StatusMessage::from("Save the file once before saving with sudo.".to_owned());
+            return false;
+        }
+
+        // The helper may need the terminal to ask for a password
+        Terminal::clear_screen();
+        Terminal::cursor_position(&Position::default());
+        let _ = Terminal::flush();
+        let _ = self.terminal.suspend_raw_mode();
+        let command = self.config.sudo_command.clone();
+        let result = self.document.save_with_command(&command);
+        let _ = self.terminal.activate_raw_mode();
+
+        match result {
+            Ok(()) => {
+                self.remove_recovery();
+                self.status_message = StatusMessage::from(format!("File saved with `{command}`."));
+                true
+            },
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: Could not save file: {error}"));
+                false
+            },
+        }
+    }
+
+    fn toggle_selection(&mut self) {
+        // Only the primary cursor selects
+        self.cursors.clear();
+        if self.selection_anchor.is_some() {
+            self.selection_anchor = None;
+            self.status_message = StatusMessage::from("Selection cleared.".to_owned());
+        } else {
+            self.selection_anchor = Some(self.cursor_position.clone());
+            self.status_message = StatusMessage::from(format!(
+                "Selection started, {} or {} to clear it.",
+                self.keymap.keys_for(Action::ToggleSelection),
+                self.keymap.keys_for(Action::ClearSelection)
+            ));
+        }
+    }
+
+    fn toggle_comment(&mut self) {
+        if self.document.is_read_only() {
+            self.refuse_read_only();
+            return;
+        }
+        let rows = self.selected_rows();
+        let Some(moves) = self.document.toggle_comment(rows.clone()) else {
+            self.status_message = StatusMessage::from(format!("No comment syntax for {}.", self.document.file_type()));
+            return;
+        };
+
+        // Keep the cursor and anchor on the same characters, or at the
+        // comment's place when it was on the removed token
+        for position in [Some(&mut self.cursor_position), self.selection_anchor.as_mut()].into_iter().flatten() {
+            if let Some((column, delta)) = moves.get(position.y.wrapping_sub(rows.start)) {
+                if position.x >= *column {
+                    position.x = position.x.saturating_add_signed(*delta).max(*column);
+                }
+            }
+        }
+        self.move_cursor(Key::Null);
+    }
+
+    fn indent_selection(&mut self, indent: bool) {
+        let rows = self.selected_rows();
+        let amounts = if indent {
+            self.document.indent_rows(rows.clone())
+        } else {
+            self.document.outdent_rows(rows.clone())
+        };
+
+        for position in [Some(&mut self.cursor_position), self.selection_anchor.as_mut()].into_iter().flatten() {
+            if let Some(amount) = amounts.get(position.y.wrapping_sub(rows.start)) {
+                position.x = if indent {
+                    position.x.saturating_add(*amount)
+                } else {
+                    position.x.saturating_sub(*amount)
+                };
+            }
+        }
+        self.move_cursor(Key::Null);
+    }
+
+    fn duplicate_rows(&mut self) {
+        let rows = self.selected_rows();
+        if !self.document.duplicate_rows(rows.clone()) {
+            return;
+        }
+        // Carry on from the copy
+        for position in [Some(&mut self.cursor_position), self.selection_anchor.as_mut()].into_iter().flatten() {
+            position.y = position.y.saturating_add(rows.len());
+        }
+    }
+
+    fn move_rows(&mut self, up: bool) {
+        if !self.document.move_rows(self.selected_rows(), up) {
+            return;
+        }
+        for position in [Some(&mut self.cursor_position), self.selection_anchor.as_mut()].into_iter().flatten() {
+            position.y = if up {
+                position.y.saturating_sub(1)
+            } else {
+                position.y.saturating_add(1)
+            };
+        }
+    }
+
+    fn join_rows(&mut self) {
+        if let Some(position) = self.document.join_rows(self.selected_rows()) {
+            self.selection_anchor = None;
+            self.cursor_position = position;
+        }
+    }
+
+    fn delete_rows(&mut self) {
+        let rows = self.selected_rows();
+        self.document.delete_rows(rows.clone());
+        self.selection_anchor = None;
+        self.cursor_position.y = rows.start;
+        self.move_cursor(Key::Null);
+    }
+
+    fn sort_rows(&mut self) {
+        let flags = self
+            .prompt("Sort (l)exically, (n)umerically, (i)gnoring case, (k)eeping order; (u)nique, (r)everse: ", "l", |_, _, _| {})
+            .unwrap_or(None);
+        let Some(flags) = flags else {
+            return;
+        };
+
+        let mut options = SortOptions {
+            key: SortKey::Lexical,
+            unique: false,
+            reverse: false,
+        };
+        for flag in flags.chars() {
+            match flag {
+                'l' => options.key = SortKey::Lexical,
+                'n' => options.key = SortKey::Numeric,
+                'i' => options.key = SortKey::IgnoreCase,
+                'k' => options.key = SortKey::Unsorted,
+                'u' => options.unique = true,
+                'r' => options.reverse = true,
+                _ => {
+                    self.status_message = StatusMessage::from(format!("Unknown sort flag: {flag}"));
+                    return;
+                },
+            }
+        }
+
+        // Without a selection, the whole file is sorted
+        let rows = if self.selection_anchor.is_some() {
+            self.selected_rows()
+        } else {
+            0..self.document.len()
+        };
+        self.document.sort_rows(rows, &options);
+        self.selection_anchor = None;
+        self.move_cursor(Key::Null);
+    }
+
+    fn undo(&mut self, undo: bool) {
+        let position = if undo {
+            self.document.undo()
+        } else {
+            self.document.redo()
+        };
+        if let Some(position) = position {
+            self.selection_anchor = None;
+            self.cursors.clear();
+            self.cursor_position = position;
+            self.move_cursor(Key::Null);
+        } else if undo {
+            self.status_message = StatusMessage::from("Nothing to undo.".to_owned());
+        } else {
+            self.status_message = StatusMessage::from("Nothing to redo.".to_owned());
+        }
+    }
+
+    fn toggle_soft_wrap(&mut self) {
+        self.config.soft_wrap = !self.config.soft_wrap;
+        self.offset = Position {
+            x: 0,
+            y: self.offset.y,
+        };
+        self.wrap_offset = 0;
+        self.status_message = StatusMessage::from(if self.config.soft_wrap {
+            "Soft wrap enabled.".to_owned()
+        } else {
+            "Soft wrap disabled.".to_owned()
+        });
+    }
+
+    fn toggle_read_only(&mut self) {
+        let read_only = !self.document.is_read_only();
+        self.document.set_read_only(read_only);
+        self.status_message = StatusMessage::from(if read_only {
+            "File is now read-only.".to_owned()
+        } else {
+            "File can now be edited.".to_owned()
+        });
+    }
+
+    fn change_encoding(&mut self) {
+        let label = self.prompt("Encoding: ", "", |_, _, _| {}).unwrap_or(None);
+        let Some(label) = label else {
+            return;
+        };
+        let Some(encoding) = FileEncoding::for_label(&label) else {
+            self.status_message = StatusMessage::from(format!("Unknown encoding: {label}"));
+            return;
+        };
+
+        if self.document.set_encoding(encoding) {
+            self.status_message = StatusMessage::from(format!("File reloaded as {}.", self.document.encoding()));
+            self.move_cursor(Key::Null);
+        } else {
+            self.status_message = StatusMessage::from(format!("File will be saved as {}.", self.document.encoding()));
         }
     }
 
     fn search(&mut self) {
         let old_position = self.cursor_position.clone();
         let mut direction = SearchDirection::Forward;
+        self.prompt_flags = self.search_options.flags();
         let query = self
             .prompt(
-                "Search (ESC to cancel, Arrows to navigate): ",
+                "Search (Ctrl-N/P: next/prev, Alt-C/S/W: flags): ",
                 "",
-                |editor, key, query: &String| {
+                |editor, key, query: &mut String| {
                     let mut moved = false;
+                    let options = &mut editor.search_options;
                     match key {
-                        Key::Right | Key::Down => {
+                        Key::Ctrl('n') => {
                             direction = SearchDirection::Forward;
                             editor.move_cursor(Key::Right);
                             moved = true;
                         }
-                        Key::Left | Key::Up => direction = SearchDirection::Backward,
+                        Key::Ctrl('p') => direction = SearchDirection::Backward,
+                        Key::Alt('c') => options.ignore_case = !options.ignore_case,
+                        Key::Alt('s') => options.smart_case = !options.smart_case,
+                        Key::Alt('w') => options.whole_word = !options.whole_word,
                         _ => direction = SearchDirection::Forward,
                     }
-                    if let Some(position) =
-                        editor
-                            .document
-                            .find(&query, &editor.cursor_position, direction)
-                    {
+                    editor.prompt_flags = editor.search_options.flags();
+
+                    let options = editor.search_options;
+                    let mut found = editor.document.find(query, &editor.cursor_position, direction, &options);
+                    let mut wrapped = false;
+                    // Past the last match, carry on from the other end of the file
+                    if found.is_none() {
+                        let start = match direction {
+                            SearchDirection::Forward => Position::default(),
+                            SearchDirection::Backward => {
+                                let y = editor.document.len().saturating_sub(1);
+                                Position { x: editor.document.row(y).map_or(0, Row::len), y }
+                            },
+                        };
+                        found = editor.document.find(query, &start, direction, &options);
+                        wrapped = found.is_some();
+                    }
+                    if let Some(position) = found {
                         editor.cursor_position = position;
                         editor.scroll();
                     } else if moved {
                         editor.move_cursor(Key::Left);
                     }
-                    editor.document.highlight(Some(query));
+
+                    // Found once for the count and the highlighting
+                    let matches = editor.document.find_all(query, &options);
+                    let (current, total) = count_matches(&matches, &editor.cursor_position);
+                    editor.prompt_info = match total {
+                        _ if query.is_empty() => String::new(),
+                        0 => " (no matches)".to_owned(),
+                        _ if wrapped => format!(" (match {current} of {total}, search wrapped)"),
+                        _ => format!(" (match {current} of {total})"),
+                    };
+                    let visible = editor.offset.y..editor.offset.y.saturating_add(editor.terminal.size().height as usize);
+                    editor.document.highlight(Some((&matches, query.graphemes(true).count())), visible);
                 },
             )
             .unwrap_or(None);
 
+        self.prompt_flags.clear();
+        self.prompt_info.clear();
         if query.is_none() {
             self.cursor_position = old_position;
             self.scroll();
         }
-        self.document.highlight(None);
+        self.document.highlight(None, 0..0);
+    }
+
+    fn command_line(&mut self) {
+        let mut completion = None;
+        let scripts = self.scripts.commands();
+        let line = self
+            .prompt(":", "", |_, key, line: &mut String| {
+                if key == Key::Char('\t') {
+                    complete_command(line, &mut completion, &scripts);
+                } else {
+                    completion = None;
+                }
+            })
+            .unwrap_or(None);
+        if let Some(line) = line.filter(|line| !line.trim().is_empty()) {
+            self.run_command(line.trim());
+        }
+    }
+
+    fn run_command(&mut self, line: &str) {
+        let (name, argument) = line.split_once(' ').map_or((line, ""), |(name, argument)| (name, argument.trim()));
+        match name {
+            "goto" => match argument.parse::<usize>() {
+                Ok(line) => {
+                    self.selection_anchor = None;
+                    self.cursor_position = Position {
+                        x: 0,
+                        y: std::cmp::min(line.saturating_sub(1), self.document.len().saturating_sub(1)),
+                    };
+                },
+                Err(_) => self.status_message = StatusMessage::from("Usage: goto LINE".to_owned()),
+            },
+            "open" | "open!" => self.open(argument, name == "open!"),
+            "w" if argument.is_empty() => self.save(false),
+            "w" => self.save_as(argument.to_owned()),
+            "wq" => {
+                self.save(false);
+                self.should_quit = !self.document.is_dirty();
+            },
+            "q" | "quit" if self.document.is_dirty() => {
+                self.status_message = StatusMessage::from("File has unsaved changes, q! quits anyway.".to_owned());
+            },
+            "q" | "quit" | "q!" => self.should_quit = true,
+            "set" => self.set_option(argument),
+            _ => match Action::from_name(name) {
+                Some(action) if action.edits() && self.document.is_read_only() => self.refuse_read_only(),
+                Some(action) => self.run_action(action),
+                None if self.scripts.has_command(name) => self.run_script(name, Vec::new()),
+                None => self.status_message = StatusMessage::from(format!("Unknown command: {name}")),
+            },
+        }
+    }
+
+    // Script edits are undone in one go
+    fn run_script(&mut self, function: &str, args: Vec<Dynamic>) {
+        let changes = self.document.changes();
+        self.document.begin_group();
+        let result = self.scripts.call(&mut self.document, &mut self.cursor_position, function, args);
+        self.document.end_group();
+        if self.document.changes() != changes {
+            self.selection_anchor = None;
+            self.cursors.clear();
+        }
+        self.script_done(result);
+    }
+
+    // Carries out what the script asked for once it returned
+    fn script_done(&mut self, result: Result<Vec<Request>, String>) {
+        self.move_cursor(Key::Null);
+        let requests = match result {
+            Ok(requests) => requests,
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: Script failed: {error}"));
+                return;
+            },
+        };
+        for request in requests {
+            match request {
+                Request::Message(text) => self.status_message = StatusMessage::from(text),
+                Request::Run(action) if action.edits() && self.document.is_read_only() => self.refuse_read_only(),
+                // Quitting from a script would skip the unsaved changes warning
+                Request::Run(Action::Quit) if self.document.is_dirty() => {
+                    self.status_message = StatusMessage::from("File has unsaved changes, not quitting.".to_owned());
+                },
+                Request::Run(action) => self.run_action(action),
+                Request::Prompt(text, function) => {
+                    if let Some(answer) = self.prompt(&text, "", |_, _, _| {}).unwrap_or(None) {
+                        self.run_script(&function, vec![Dynamic::from(answer)]);
+                    }
+                },
+                Request::Bind(keys, function) => {
+                    if let Err(error) = self.keymap.bind_script(&keys, &function) {
+                        self.status_message = StatusMessage::from(format!("ERR: {error}"));
+                    }
+                },
+            }
+        }
+    }
+
+    fn open(&mut self, filename: &str, discard_changes: bool) {
+        if filename.is_empty() {
+            self.status_message = StatusMessage::from("Usage: open FILE".to_owned());
+            return;
+        }
+        if self.document.is_dirty() && !discard_changes {
+            self.status_message = StatusMessage::from("File has unsaved changes, open! discards them.".to_owned());
+            return;
+        }
+
+        let document = match Document::open(filename, &self.config) {
+            Ok(document) => {
+                self.status_message = StatusMessage::from(format!("Opened {filename}."));
+                document
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                self.status_message = StatusMessage::from(format!("New file {filename}."));
+                let mut document = Document::default();
+                document.filename = Some(filename.to_owned());
+                document.set_indentation(self.config.indentation);
+                document
+            },
+            Err(error) => {
+                self.status_message = StatusMessage::from(format!("ERR: Could not open file {filename}: {error}"));
+                return;
+            },
+        };
+        self.remove_recovery();
+        self.document = document;
+        self.cursor_position = Position::default();
+        self.selection_anchor = None;
+        self.cursors.clear();
+        self.offset = Position::default();
+        self.wrap_offset = 0;
+        self.check_recovery();
+    }
+
+    // `set tab_width 4` or `set tab_width = 4`
+    fn set_option(&mut self, argument: &str) {
+        let (name, value) = argument
+            .split_once(['=', ' '])
+            .map_or((argument, ""), |(name, value)| (name.trim(), value.trim()));
+        let Some(key) = config::option_name(name) else {
+            self.status_message = StatusMessage::from(format!("Unknown option: {name}"));
+            return;
+        };
+        if let Err(error) = self.config.set(key, value) {
+            self.status_message = StatusMessage::from(format!("ERR: {error}"));
+            return;
+        }
+
+        // Indentation options apply to the open file as well
+        let mut indentation = self.document.indentation();
+        match key {
+            "tab_width" => indentation.tab_width = self.config.indentation.tab_width,
+            "indent_width" => indentation.indent_width = self.config.indentation.indent_width,
+            "expand_tabs" => indentation.expand_tabs = self.config.indentation.expand_tabs,
+            _ => (),
+        }
+        self.document.set_indentation(indentation);
+        self.status_message = StatusMessage::from(format!("{key} = {value}"));
+    }
+
+    fn check_disk_changes(&mut self) {
+        if !self.document.changed_on_disk() {
+            return;
+        }
+
+        if !self.document.is_dirty() && self.config.auto_reload {
+            self.reload();
+            return;
+        }
+
+        let answer = self
+            .prompt("File changed on disk: (r)eload it or (k)eep your version? ", "", |_, _, _| {})
+            .unwrap_or(None);
+        if answer.as_deref() == Some("r") {
+            self.reload();
+        } else {
+            self.document.ignore_disk_changes();
+            self.status_message = StatusMessage::from("Kept your version, saving will overwrite the file.".to_owned());
+        }
+    }
+
+    fn reload(&mut self) {
+        match self.document.reload(&self.config) {
+            Ok(()) => {
+                self.status_message = StatusMessage::from("File changed on disk, reloaded.".to_owned());
+                self.cursors.clear();
+                self.move_cursor(Key::Null);
+            },
+            Err(error) => {
+                self.document.ignore_disk_changes();
+                self.status_message = StatusMessage::from(format!("ERR: Could not reload file: {error}"));
+            },
+        }
+    }
+
+    fn recovery(&self) -> Option<Recovery> {
+        self.document.filename.as_deref().and_then(Recovery::for_file)
+    }
+
+    fn write_recovery(&mut self) {
+        self.last_recovery_write = Instant::now();
+        if !self.document.is_dirty() {
+            return;
+        }
+        if let (Some(recovery), Some(filename)) = (self.recovery(), &self.document.filename) {
+            if let Err(error) = recovery.write(filename, &self.document.contents()) {
+                self.status_message = StatusMessage::from(format!("ERR: Could not write recovery file: {error}"));
+            }
+        }
+    }
+
+    fn remove_recovery(&self) {
+        if let Some(recovery) = self.recovery() {
+            recovery.remove();
+        }
+    }
+
+    fn check_recovery(&mut self) {
+        let Some(recovery) = self.recovery() else {
+            return;
+        };
+        let Some(recovered) = recovery.read() else {
+            return;
+        };
+        let current = self.document.contents();
+        if recovered == current {
+            recovery.remove();
+            return;
+        }
+
+        let mut question = "Unsaved changes found: (r)ecover, (d)iff or (x) discard them? ".to_owned();
+        loop {
+            let answer = self.prompt(&question, "", |_, _, _| {}).unwrap_or(None);
+            match answer.as_deref() {
+                Some("r") => {
+                    self.document.recover(&recovered);
+                    self.status_message = StatusMessage::from("Recovered unsaved changes.".to_owned());
+                    return;
+                },
+                Some("d") => {
+                    let ranges: Vec<_> = recovery::changed_lines(&current, &recovered)
+                        .iter()
+                        .map(|(start, end)| if start == end { format!("{start}") } else { format!("{start}-{end}") })
+                        .collect();
+                    question = format!("Changed lines: {}. (r)ecover or (x) discard? ", ranges.join(", "));
+                },
+                Some("x") => {
+                    recovery.remove();
+                    self.status_message = StatusMessage::from("Discarded unsaved changes.".to_owned());
+                    return;
+                },
+                Some(_) => (),
+                // Left alone, so the choice comes back next time
+                None => return,
+            }
+        }
     }
 
-    fn exit_on_error(&self, error: std::io::Error) {
+    fn exit_on_error(&mut self, error: std::io::Error) {
+        self.write_recovery();
         print!("{}", termion::clear::All);
         panic!("{error:?}");
     }
 
     fn exit(&self) {
+        self.remove_recovery();
         Terminal::clear_screen();
         Terminal::cursor_position(&Position::default());
         println!("Salut!\r");
         Terminal::flush().unwrap();
     }
 }
+
+// Completes the last word of a command line. When several commands fit, their
+// common start is added, then further Tabs cycle through them
+// `scripts` are the script functions usable as commands
+fn complete_command(line: &mut String, completion: &mut Option<(String, usize)>, scripts: &[String]) {
+    let typed = completion.as_ref().map_or_else(|| line.clone(), |(typed, _)| typed.clone());
+    let (head, word) = typed.rsplit_once(' ').map_or(("", typed.as_str()), |(head, word)| (head, word));
+    let mut candidates: Vec<&str> = match head {
+        "" => {
+            let mut names: Vec<&str> = COMMANDS.iter().copied().chain(keymap::action_names()).collect();
+            names.extend(scripts.iter().map(String::as_str));
+            names
+        },
+        "set" => config::OPTIONS.to_vec(),
+        _ => Vec::new(),
+    };
+    candidates.retain(|candidate| candidate.replace('_', "").starts_with(&word.replace('_', "")));
+    candidates.sort_unstable();
+    let head = if head.is_empty() { String::new() } else { format!("{head} ") };
+
+    match candidates[..] {
+        [] => (),
+        [candidate] => *line = format!("{head}{candidate} "),
+        [first, ..] => {
+            let common = candidates.iter().fold(first, |common, candidate| {
+                let length = common.chars().zip(candidate.chars()).take_while(|(a, b)| a == b).count();
+                &common[..common.char_indices().nth(length).map_or(common.len(), |(index, _)| index)]
+            });
+            if completion.is_none() && common.len() > word.len() {
+                *line = format!("{head}{common}");
+                return;
+            }
+            let index = completion.as_ref().map_or(0, |(_, index)| index.saturating_add(1) % candidates.len());
+            *line = format!("{head}{}", candidates[index]);
+            *completion = Some((typed.clone(), index));
+        },
+    }
+}
+
+// Drops the start of the text until its end fits within `width`, keeping
+// what comes before `column` on screen
+// The number of the match at or before `at`, and the number of matches
+fn count_matches(matches: &[Vec<usize>], at: &Position) -> (usize, usize) {
+    let mut current = 0;
+    let mut total = 0_usize;
+    for (y, row) in matches.iter().enumerate() {
+        for x in row {
+            total = total.saturating_add(1);
+            if (y, *x) <= (at.y, at.x) {
+                current = total;
+            }
+        }
+    }
+    (current, total)
+}
+
+fn scroll_to_column(text: &str, column: usize, width: usize) -> (String, usize) {
+    let mut column = column;
+    let mut graphemes = text.graphemes(true);
+    while column >= width || (column > 0 && graphemes.as_str().width() > width) {
+        match graphemes.next() {
+            Some(grapheme) => column = column.saturating_sub(grapheme.width()),
+            None => break,
+        }
+    }
+    (graphemes.as_str().to_owned(), column)
+}
+
+fn truncate_to_width(text: &str, width: usize) -> String {
+    let mut result = String::new();
+    let mut result_width: usize = 0;
+    for grapheme in text.graphemes(true) {
+        result_width = result_width.saturating_add(grapheme.width());
+        if result_width > width {
+            break;
+        }
+        result.push_str(grapheme);
+    }
+    result
+}
@@ -0,0 +1,96 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+#[derive(Clone, Copy)]
+pub struct FileEncoding {
+    encoding: &'static Encoding,
+    bom: bool,
+}
+
+impl FileEncoding {
+    pub fn for_label(label: &str) -> Option<Self> {
+        Encoding::for_label(label.trim().as_bytes()).map(Self::from)
+    }
+
+    // BOM first, then UTF-8 validity, and the legacy encoding as a last resort
+    pub fn detect(bytes: &[u8], legacy: Self) -> Self {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            return Self {
+                encoding,
+                bom: true,
+            };
+        }
+        if std::str::from_utf8(bytes).is_ok() {
+            return Self::default();
+        }
+        Self::from(legacy.encoding)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        // A BOM of another encoding is content read in this one
+        let bytes = match Encoding::for_bom(bytes) {
+            Some((encoding, bom_length)) if self.bom && encoding == self.encoding => &bytes[bom_length..],
+            _ => bytes,
+        };
+        let (content, _) = self.encoding.decode_without_bom_handling(bytes);
+        content.into_owned()
+    }
+
+    // Characters that can't be represented are reported instead of being silently replaced
+    pub fn encode(&self, content: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        if self.encoding == UTF_16LE || self.encoding == UTF_16BE {
+            if self.bom {
+                bytes.extend_from_slice(&self.encode_utf16_unit(0xFEFF));
+            }
+            for unit in content.encode_utf16() {
+                bytes.extend_from_slice(&self.encode_utf16_unit(unit));
+            }
+            return Ok(bytes);
+        }
+
+        if self.bom && self.encoding == UTF_8 {
+            bytes.extend_from_slice(b"\xEF\xBB\xBF");
+        }
+        let (encoded, _, had_errors) = self.encoding.encode(content);
+        if had_errors {
+            return Err(format!("Some characters can't be encoded in {}", self.name()));
+        }
+        bytes.extend_from_slice(&encoded);
+        Ok(bytes)
+    }
+
+    fn encode_utf16_unit(&self, unit: u16) -> [u8; 2] {
+        if self.encoding == UTF_16BE {
+            unit.to_be_bytes()
+        } else {
+            unit.to_le_bytes()
+        }
+    }
+
+    pub fn name(&self) -> String {
+        if self.bom {
+            format!("{} BOM", self.encoding.name())
+        } else {
+            self.encoding.name().to_owned()
+        }
+    }
+}
+
+impl From<&'static Encoding> for FileEncoding {
+    fn from(encoding: &'static Encoding) -> Self {
+        Self {
+            encoding,
+            // UTF-16 files can't be told apart without their BOM
+            bom: encoding == UTF_16LE || encoding == UTF_16BE,
+        }
+    }
+}
+
+impl Default for FileEncoding {
+    fn default() -> Self {
+        Self {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
@@ -3,6 +3,9 @@
 pub struct FileType {
     name: String,
     hl_opts: HighlightingOptions,
+    indent_after: Vec<char>,
+    dedent_on: Vec<char>,
+    auto_pairs: Vec<(char, char)>,
 }
 
 #[derive(Default)]
@@ -11,6 +14,8 @@ pub struct HighlightingOptions {
     strings: bool,
     characters: bool,
     comments: bool,
+    line_comment: Option<String>,
+    block_comment: Option<(String, String)>,
     primary_keywords: Vec<String>,
     secondary_keywords: Vec<String>,
 }
@@ -32,6 +37,14 @@ impl HighlightingOptions {
         self.comments
     }
 
+    pub fn line_comment(&self) -> Option<&String> {
+        self.line_comment.as_ref()
+    }
+
+    pub fn block_comment(&self) -> Option<&(String, String)> {
+        self.block_comment.as_ref()
+    }
+
     pub fn primary_keywords(&self) -> &Vec<String> {
         &self.primary_keywords
     }
@@ -49,6 +62,18 @@ impl FileType {
     pub fn highlighting_options(&self) -> &HighlightingOptions {
         &self.hl_opts
     }
+
+    pub fn indents_after(&self, c: char) -> bool {
+        self.indent_after.contains(&c)
+    }
+
+    pub fn dedents_on(&self, c: char) -> bool {
+        self.dedent_on.contains(&c)
+    }
+
+    pub fn auto_pairs(&self) -> &Vec<(char, char)> {
+        &self.auto_pairs
+    }
 }
 
 impl From<&str> for FileType {
@@ -61,6 +86,8 @@ impl From<&str> for FileType {
                     strings: true,
                     characters: true,
                     comments: true,
+                    line_comment: Some("//".to_owned()),
+                    block_comment: Some(("/*".to_owned(), "*/".to_owned())),
                    primary_keywords: vec![
                         "as".to_string(),
                         "break".to_string(),
@@ -130,7 +157,74 @@ impl From<&str> for FileType {
                         "f32".to_string(),
                         "f64".to_string(), 
                     ]
-                }
+                },
+                indent_after: vec!['{', '(', '['],
+                dedent_on: vec!['}', ')', ']'],
+                // No single quotes: they open lifetimes far more often than chars
+                auto_pairs: vec![('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')],
+            }
+        } else if file_name.ends_with(".py") {
+            Self {
+                name: "Python".to_owned(),
+                hl_opts: HighlightingOptions {
+                    numbers: true,
+                    strings: true,
+                    characters: false,
+                    comments: true,
+                    line_comment: Some("#".to_owned()),
+                    block_comment: None,
+                    primary_keywords: vec![
+                        "and".to_string(),
+                        "as".to_string(),
+                        "assert".to_string(),
+                        "async".to_string(),
+                        "await".to_string(),
+                        "break".to_string(),
+                        "class".to_string(),
+                        "continue".to_string(),
+                        "def".to_string(),
+                        "del".to_string(),
+                        "elif".to_string(),
+                        "else".to_string(),
+                        "except".to_string(),
+                        "False".to_string(),
+                        "finally".to_string(),
+                        "for".to_string(),
+                        "from".to_string(),
+                        "global".to_string(),
+                        "if".to_string(),
+                        "import".to_string(),
+                        "in".to_string(),
+                        "is".to_string(),
+                        "lambda".to_string(),
+                        "None".to_string(),
+                        "nonlocal".to_string(),
+                        "not".to_string(),
+                        "or".to_string(),
+                        "pass".to_string(),
+                        "raise".to_string(),
+                        "return".to_string(),
+                        "True".to_string(),
+                        "try".to_string(),
+                        "while".to_string(),
+                        "with".to_string(),
+                        "yield".to_string(),
+                    ],
+                    secondary_keywords: vec![
+                        "bool".to_string(),
+                        "bytes".to_string(),
+                        "dict".to_string(),
+                        "float".to_string(),
+                        "int".to_string(),
+                        "list".to_string(),
+                        "set".to_string(),
+                        "str".to_string(),
+                        "tuple".to_string(),
+                    ]
+                },
+                indent_after: vec![':', '{', '(', '['],
+                dedent_on: vec!['}', ')', ']'],
+                auto_pairs: vec![('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')],
             }
         } else {
             Self::default()
@@ -143,6 +237,9 @@ impl Default for FileType {
         Self {
             name: String::from("No file type"),
             hl_opts: HighlightingOptions::default(),
+            indent_after: Vec::new(),
+            dedent_on: Vec::new(),
+            auto_pairs: vec![('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')],
         }
     }
 }
@@ -1,5 +1,7 @@
 use termion::color;
 
+pub const SELECTION_BG_COLOR: color::Rgb = color::Rgb(68, 71, 90);
+
 #[derive(PartialEq)]
 pub enum Type {
     Number,
@@ -9,6 +11,7 @@ pub enum Type {
     Comment,
     PrimaryKeyword,
     SecondaryKeyword,
+    MatchingBracket,
     None,
 }
 
@@ -22,6 +25,7 @@ impl Type {
             Type::Comment => color::Rgb(133, 153, 0),
             Type::PrimaryKeyword => color::Rgb(181, 137, 0),
             Type::SecondaryKeyword => color::Rgb(42, 161, 152),
+            Type::MatchingBracket => color::Rgb(255, 135, 0),
             Type::None => color::Rgb(255, 255, 255),
         }
     }
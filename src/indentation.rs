@@ -0,0 +1,75 @@
+#[derive(Clone, Copy)]
+pub struct Indentation {
+    pub tab_width: usize,
+    pub indent_width: usize,
+    pub expand_tabs: bool,
+}
+
+impl Indentation {
+    // Guesses tabs vs spaces from the leading whitespace of the lines, and the
+    // indent width from the most common step between two indented lines
+    pub fn detect<'a>(&self, lines: impl Iterator<Item = &'a str>) -> Self {
+        let mut tab_lines = 0_usize;
+        let mut space_lines = 0_usize;
+        let mut steps = [0_usize; 9];
+        let mut previous_spaces = 0;
+
+        for line in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with('\t') {
+                tab_lines = tab_lines.saturating_add(1);
+                continue;
+            }
+            let spaces = line.len().saturating_sub(line.trim_start_matches(' ').len());
+            if spaces > 0 {
+                space_lines = space_lines.saturating_add(1);
+            }
+            let step = spaces.abs_diff(previous_spaces);
+            if let Some(count) = steps.get_mut(step) {
+                if step > 1 {
+                    *count = count.saturating_add(1);
+                }
+            }
+            previous_spaces = spaces;
+        }
+
+        let mut detected = *self;
+        if tab_lines > space_lines {
+            detected.expand_tabs = false;
+        } else if space_lines > tab_lines {
+            detected.expand_tabs = true;
+            if let Some((step, _)) = steps.iter().enumerate().filter(|(_, count)| **count > 0).max_by_key(|(_, count)| **count) {
+                detected.indent_width = step;
+            }
+        }
+        detected
+    }
+
+    pub fn unit(&self) -> String {
+        if self.expand_tabs {
+            " ".repeat(self.indent_width)
+        } else {
+            "\t".to_owned()
+        }
+    }
+
+    pub fn name(&self) -> String {
+        if self.expand_tabs {
+            format!("Spaces: {}", self.indent_width)
+        } else {
+            format!("Tab: {}", self.tab_width)
+        }
+    }
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            indent_width: 4,
+            expand_tabs: false,
+        }
+    }
+}
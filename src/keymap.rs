@@ -0,0 +1,388 @@
+use std::fs;
+
+use termion::event::Key;
+
+use crate::Config;
+
+const KEYMAP_FILE: &str = "keymap";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Save,
+    SaveAs,
+    SaveWithSudo,
+    Search,
+    ChangeEncoding,
+    ToggleReadOnly,
+    ToggleSoftWrap,
+    MatchingBracket,
+    ToggleSelection,
+    ClearSelection,
+    ToggleComment,
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    PageUp,
+    PageDown,
+    LineStart,
+    LineEnd,
+    Delete,
+    Backspace,
+    Newline,
+    Tab,
+    Outdent,
+    DuplicateRows,
+    MoveRowsUp,
+    MoveRowsDown,
+    JoinRows,
+    DeleteRows,
+    SortRows,
+    Undo,
+    Redo,
+    CommandLine,
+    AddCursorAtNext,
+    AddCursorAbove,
+    AddCursorBelow,
+    RecordMacro,
+    PlayMacro,
+    SaveMacros,
+    // Calls the script function bound to keys, by its index in the keymap
+    Script(usize),
+}
+
+const ACTIONS: [(&str, Action); 40] = [
+    ("quit", Action::Quit),
+    ("save", Action::Save),
+    ("save_as", Action::SaveAs),
+    ("save_with_sudo", Action::SaveWithSudo),
+    ("search", Action::Search),
+    ("change_encoding", Action::ChangeEncoding),
+    ("toggle_read_only", Action::ToggleReadOnly),
+    ("toggle_soft_wrap", Action::ToggleSoftWrap),
+    ("matching_bracket", Action::MatchingBracket),
+    ("toggle_selection", Action::ToggleSelection),
+    ("clear_selection", Action::ClearSelection),
+    ("toggle_comment", Action::ToggleComment),
+    ("move_up", Action::MoveUp),
+    ("move_down", Action::MoveDown),
+    ("move_left", Action::MoveLeft),
+    ("move_right", Action::MoveRight),
+    ("page_up", Action::PageUp),
+    ("page_down", Action::PageDown),
+    ("line_start", Action::LineStart),
+    ("line_end", Action::LineEnd),
+    ("delete", Action::Delete),
+    ("backspace", Action::Backspace),
+    ("newline", Action::Newline),
+    ("tab", Action::Tab),
+    ("outdent", Action::Outdent),
+    ("duplicate_rows", Action::DuplicateRows),
+    ("move_rows_up", Action::MoveRowsUp),
+    ("move_rows_down", Action::MoveRowsDown),
+    ("join_rows", Action::JoinRows),
+    ("delete_rows", Action::DeleteRows),
+    ("sort_rows", Action::SortRows),
+    ("undo", Action::Undo),
+    ("redo", Action::Redo),
+    ("command_line", Action::CommandLine),
+    ("add_cursor_at_next", Action::AddCursorAtNext),
+    ("add_cursor_above", Action::AddCursorAbove),
+    ("add_cursor_below", Action::AddCursorBelow),
+    ("record_macro", Action::RecordMacro),
+    ("play_macro", Action::PlayMacro),
+    ("save_macros", Action::SaveMacros),
+];
+
+const DEFAULT_BINDINGS: [(Key, Action); 40] = [
+    (Key::Ctrl('q'), Action::Quit),
+    (Key::Ctrl('s'), Action::Save),
+    (Key::Alt('s'), Action::SaveAs),
+    (Key::Alt('w'), Action::SaveWithSudo),
+    (Key::Ctrl('f'), Action::Search),
+    (Key::Alt('e'), Action::ChangeEncoding),
+    (Key::Alt('r'), Action::ToggleReadOnly),
+    (Key::Alt('z'), Action::ToggleSoftWrap),
+    (Key::Alt('m'), Action::MatchingBracket),
+    (Key::Null, Action::ToggleSelection),
+    (Key::Esc, Action::ClearSelection),
+    // Terminals send Ctrl-/ as Ctrl-_, which termion reads as Ctrl-7
+    (Key::Ctrl('7'), Action::ToggleComment),
+    (Key::Alt('/'), Action::ToggleComment),
+    (Key::Up, Action::MoveUp),
+    (Key::Down, Action::MoveDown),
+    (Key::Left, Action::MoveLeft),
+    (Key::Right, Action::MoveRight),
+    (Key::PageUp, Action::PageUp),
+    (Key::PageDown, Action::PageDown),
+    (Key::Home, Action::LineStart),
+    (Key::End, Action::LineEnd),
+    (Key::Delete, Action::Delete),
+    (Key::Backspace, Action::Backspace),
+    (Key::Char('\n'), Action::Newline),
+    (Key::Char('\t'), Action::Tab),
+    (Key::BackTab, Action::Outdent),
+    (Key::Ctrl('d'), Action::DuplicateRows),
+    (Key::Alt('k'), Action::MoveRowsUp),
+    (Key::Alt('j'), Action::MoveRowsDown),
+    (Key::Alt('J'), Action::JoinRows),
+    (Key::Ctrl('k'), Action::DeleteRows),
+    (Key::Alt('o'), Action::SortRows),
+    (Key::Ctrl('z'), Action::Undo),
+    (Key::Ctrl('y'), Action::Redo),
+    (Key::Ctrl('p'), Action::CommandLine),
+    (Key::Alt('n'), Action::AddCursorAtNext),
+    (Key::Alt('U'), Action::AddCursorAbove),
+    (Key::Alt('D'), Action::AddCursorBelow),
+    (Key::F(3), Action::RecordMacro),
+    (Key::F(4), Action::PlayMacro),
+];
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Self> {
+        ACTIONS.iter().find(|(action_name, _)| *action_name == name).map(|(_, action)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        ACTIONS.iter().find(|(_, action)| *action == self).map_or("", |(name, _)| name)
+    }
+
+    // Actions refused on read-only files
+    pub fn edits(self) -> bool {
+        matches!(
+            self,
+            Self::Delete | Self::Backspace | Self::Newline | Self::Tab | Self::Outdent | Self::DuplicateRows
+                | Self::MoveRowsUp | Self::MoveRowsDown | Self::JoinRows | Self::DeleteRows | Self::SortRows
+        )
+    }
+
+    // Run at every cursor when there are several
+    pub fn is_multi_cursor(self) -> bool {
+        matches!(
+            self,
+            Self::MoveUp | Self::MoveDown | Self::MoveLeft | Self::MoveRight | Self::LineStart | Self::LineEnd
+                | Self::Delete | Self::Backspace | Self::Newline | Self::Tab
+        )
+    }
+}
+
+pub fn action_names() -> impl Iterator<Item = &'static str> {
+    ACTIONS.iter().map(|(name, _)| *name)
+}
+
+pub enum Lookup {
+    Action(Action),
+    // The keys so far start a longer sequence
+    Pending(Vec<Key>),
+    Unbound(Vec<Key>),
+}
+
+pub struct Keymap {
+    bindings: Vec<(Vec<Key>, Action)>,
+    pending: Vec<Key>,
+    // Script functions bound to keys, indexed by `Action::Script`
+    scripts: Vec<String>,
+}
+
+impl Keymap {
+    // Bindings from the keymap file replace the defaults, lines look like `Ctrl-X Ctrl-S = save`
+    pub fn load() -> (Self, Vec<String>) {
+        let mut keymap = Self::default();
+        let mut errors = Vec::new();
+
+        let content = Config::dir()
+            .and_then(|dir| fs::read_to_string(dir.join(KEYMAP_FILE)).ok())
+            .unwrap_or_default();
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.rsplit_once('=') {
+                Some((keys, action)) => keymap.bind(keys.trim(), action.trim()),
+                None => Err(format!("expected `keys = command`, got `{line}`")),
+            };
+            if let Err(error) = result {
+                errors.push(format!("keymap line {}: {error}", line_number.saturating_add(1)));
+            }
+        }
+
+        (keymap, errors)
+    }
+
+    // `none` removes the binding. A sequence that is a prefix of another one
+    // would hide it: it is refused when both come from the keymap file, and a
+    // default binding gives way but is still reported
+    pub fn bind(&mut self, keys: &str, action: &str) -> Result<(), String> {
+        let action = match action {
+            "none" => None,
+            _ => Some(Action::from_name(action).ok_or_else(|| format!("unknown command `{action}`"))?),
+        };
+        self.bind_action(keys, action)
+    }
+
+    fn bind_action(&mut self, keys: &str, action: Option<Action>) -> Result<(), String> {
+        let sequence = keys
+            .split_whitespace()
+            .map(|name| parse_key(name).ok_or_else(|| format!("unknown key `{name}`")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if sequence.is_empty() {
+            return Err("no keys given".to_owned());
+        }
+
+        let is_default = |keys: &Vec<Key>, bound: Action| {
+            keys.len() == 1 && DEFAULT_BINDINGS.iter().any(|(key, action)| keys[0] == *key && *action == bound)
+        };
+        let conflicts = |keys: &Vec<Key>| *keys != sequence && (keys.starts_with(&sequence) || sequence.starts_with(keys));
+        if let Some((keys, bound)) = self.bindings.iter().find(|(keys, bound)| conflicts(keys) && !is_default(keys, *bound)) {
+            return Err(format!("`{}` conflicts with `{}` ({})", keys_name(&sequence), keys_name(keys), self.action_name(*bound)));
+        }
+        let replaced: Vec<String> = self
+            .bindings
+            .iter()
+            .filter(|(keys, _)| conflicts(keys))
+            .map(|(keys, bound)| format!("`{}` ({})", keys_name(keys), self.action_name(*bound)))
+            .collect();
+
+        self.bindings.retain(|(keys, _)| *keys != sequence && !conflicts(keys));
+        if let Some(action) = action {
+            self.bindings.push((sequence.clone(), action));
+        }
+        if replaced.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("`{}` replaces {}", keys_name(&sequence), replaced.join(", ")))
+        }
+    }
+
+    pub fn bind_script(&mut self, keys: &str, function: &str) -> Result<(), String> {
+        let index = self.scripts.iter().position(|bound| bound == function).unwrap_or_else(|| {
+            self.scripts.push(function.to_owned());
+            self.scripts.len().saturating_sub(1)
+        });
+        self.bind_action(keys, Some(Action::Script(index)))
+    }
+
+    pub fn script(&self, index: usize) -> Option<&str> {
+        self.scripts.get(index).map(String::as_str)
+    }
+
+    // Script actions are named after their function
+    fn action_name(&self, action: Action) -> &str {
+        match action {
+            Action::Script(index) => self.script(index).unwrap_or_default(),
+            _ => action.name(),
+        }
+    }
+
+    pub fn lookup(&mut self, key: Key) -> Lookup {
+        self.pending.push(key);
+        if let Some((_, action)) = self.bindings.iter().find(|(keys, _)| *keys == self.pending) {
+            self.pending.clear();
+            return Lookup::Action(*action);
+        }
+        if self.bindings.iter().any(|(keys, _)| keys.starts_with(&self.pending)) {
+            return Lookup::Pending(self.pending.clone());
+        }
+        Lookup::Unbound(std::mem::take(&mut self.pending))
+    }
+
+    // A key sequence is partly typed
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // The first key sequence running the action, for help texts
+    pub fn keys_for(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .find(|(_, bound)| *bound == action)
+            .map_or_else(|| "unbound".to_owned(), |(keys, _)| keys_name(keys))
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            bindings: DEFAULT_BINDINGS.iter().map(|(key, action)| (vec![*key], *action)).collect(),
+            pending: Vec::new(),
+            scripts: Vec::new(),
+        }
+    }
+}
+
+pub fn parse_key(name: &str) -> Option<Key> {
+    let single = |text: &str| {
+        let mut chars = text.chars();
+        chars.next().filter(|_| chars.next().is_none())
+    };
+    if let Some(rest) = name.strip_prefix("Ctrl-") {
+        return match rest {
+            "Space" => Some(Key::Null),
+            "/" => Some(Key::Ctrl('7')),
+            _ => single(rest).map(|c| Key::Ctrl(c.to_ascii_lowercase())),
+        };
+    }
+    if let Some(rest) = name.strip_prefix("Alt-") {
+        return match rest {
+            "Space" => Some(Key::Alt(' ')),
+            _ => single(rest).map(Key::Alt),
+        };
+    }
+    if let Some(number) = name.strip_prefix('F').and_then(|number| number.parse().ok()) {
+        return (1..=12).contains(&number).then_some(Key::F(number));
+    }
+    match name {
+        "Up" => Some(Key::Up),
+        "Down" => Some(Key::Down),
+        "Left" => Some(Key::Left),
+        "Right" => Some(Key::Right),
+        "Home" => Some(Key::Home),
+        "End" => Some(Key::End),
+        "PageUp" => Some(Key::PageUp),
+        "PageDown" => Some(Key::PageDown),
+        "Insert" => Some(Key::Insert),
+        "Delete" => Some(Key::Delete),
+        "Backspace" => Some(Key::Backspace),
+        "Enter" => Some(Key::Char('\n')),
+        "Tab" => Some(Key::Char('\t')),
+        "BackTab" => Some(Key::BackTab),
+        "Esc" => Some(Key::Esc),
+        "Space" => Some(Key::Char(' ')),
+        _ => single(name).map(Key::Char),
+    }
+}
+
+pub fn key_name(key: Key) -> String {
+    match key {
+        Key::Null => "Ctrl-Space".to_owned(),
+        Key::Ctrl('7') => "Ctrl-/".to_owned(),
+        Key::Ctrl(c) => format!("Ctrl-{}", c.to_ascii_uppercase()),
+        Key::Alt(' ') => "Alt-Space".to_owned(),
+        Key::Alt(c) => format!("Alt-{c}"),
+        Key::F(number) => format!("F{number}"),
+        Key::Char('\n') => "Enter".to_owned(),
+        Key::Char('\t') => "Tab".to_owned(),
+        Key::Char(' ') => "Space".to_owned(),
+        Key::Char(c) => c.to_string(),
+        Key::Up => "Up".to_owned(),
+        Key::Down => "Down".to_owned(),
+        Key::Left => "Left".to_owned(),
+        Key::Right => "Right".to_owned(),
+        Key::Home => "Home".to_owned(),
+        Key::End => "End".to_owned(),
+        Key::PageUp => "PageUp".to_owned(),
+        Key::PageDown => "PageDown".to_owned(),
+        Key::Insert => "Insert".to_owned(),
+        Key::Delete => "Delete".to_owned(),
+        Key::Backspace => "Backspace".to_owned(),
+        Key::BackTab => "BackTab".to_owned(),
+        Key::Esc => "Esc".to_owned(),
+        _ => "?".to_owned(),
+    }
+}
+
+pub fn keys_name(keys: &[Key]) -> String {
+    keys.iter().map(|key| key_name(*key)).collect::<Vec<_>>().join(" ")
+}
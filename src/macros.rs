@@ -0,0 +1,158 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+
+use termion::event::Key;
+
+use crate::keymap;
+use crate::Config;
+
+const MACROS_FILE: &str = "macros";
+// Playback refuses to queue more keys than this, e.g. for a macro playing
+// another one many times
+const MAX_PENDING_KEYS: usize = 100_000;
+
+#[derive(Default)]
+pub struct Macros {
+    registers: HashMap<char, Vec<Key>>,
+    recording: Option<(char, Vec<Key>)>,
+    // Number of keys recorded before the key sequence being typed
+    sequence_start: usize,
+    // Keys of the macros being played, read before the terminal
+    pending: VecDeque<Key>,
+    // Registers being played, each with the number of pending keys that follow
+    // its own. It still counts as playing while its last key is handled
+    playing: Vec<(char, usize)>,
+}
+
+impl Macros {
+    // Lines like `a = Ctrl-A x Space y` from the macros file
+    pub fn load() -> (Self, Vec<String>) {
+        let mut macros = Self::default();
+        let mut errors = Vec::new();
+
+        let content = Config::dir()
+            .and_then(|dir| fs::read_to_string(dir.join(MACROS_FILE)).ok())
+            .unwrap_or_default();
+        for (line_number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.split_once('=') {
+                Some((register, keys)) => macros.define(register.trim(), keys),
+                None => Err(format!("expected `register = keys`, got `{line}`")),
+            };
+            if let Err(error) = result {
+                errors.push(format!("macros line {}: {error}", line_number.saturating_add(1)));
+            }
+        }
+
+        (macros, errors)
+    }
+
+    fn define(&mut self, register: &str, keys: &str) -> Result<(), String> {
+        let mut chars = register.chars();
+        let register = match (chars.next(), chars.next()) {
+            (Some(register), None) if is_register(register) => register,
+            _ => return Err(format!("invalid register `{register}`")),
+        };
+        let keys = keys
+            .split_whitespace()
+            .map(|name| keymap::parse_key(name).ok_or_else(|| format!("unknown key `{name}`")))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.registers.insert(register, keys);
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let dir = Config::dir().ok_or_else(|| "no configuration directory".to_owned())?;
+        let mut registers: Vec<_> = self.registers.iter().collect();
+        registers.sort_by_key(|(register, _)| **register);
+        let content: String = registers
+            .iter()
+            .map(|(register, keys)| format!("{register} = {}\n", keymap::keys_name(keys)))
+            .collect();
+        fs::create_dir_all(&dir)
+            .and_then(|()| fs::write(dir.join(MACROS_FILE), content))
+            .map_err(|error| error.to_string())
+    }
+
+    pub fn recording(&self) -> Option<char> {
+        self.recording.as_ref().map(|(register, _)| *register)
+    }
+
+    pub fn start(&mut self, register: char) {
+        self.recording = Some((register, Vec::new()));
+        self.sequence_start = 0;
+    }
+
+    // Called before reading the first key of a key sequence
+    pub fn mark(&mut self) {
+        if let Some((_, keys)) = &self.recording {
+            self.sequence_start = keys.len();
+        }
+    }
+
+    pub fn record(&mut self, key: Key) {
+        if let Some((_, keys)) = self.recording.as_mut() {
+            keys.push(key);
+        }
+    }
+
+    // Keeps the keys recorded before the sequence that stopped the recording,
+    // returns the register and their number
+    pub fn stop(&mut self) -> Option<(char, usize)> {
+        let (register, mut keys) = self.recording.take()?;
+        keys.truncate(self.sequence_start);
+        let len = keys.len();
+        self.registers.insert(register, keys);
+        Some((register, len))
+    }
+
+    // Played keys go before those of a macro already playing, which may be
+    // the one playing this one. A macro playing itself, even through another
+    // one, would never end and stops all playback
+    pub fn play(&mut self, register: char, count: usize) -> Result<(), String> {
+        let keys = match self.registers.get(&register) {
+            Some(keys) if !keys.is_empty() => keys,
+            _ => return Err(format!("Register {register} holds no macro.")),
+        };
+        let pending = self.pending.len();
+        while self.playing.last().is_some_and(|(_, following)| *following > pending) {
+            self.playing.pop();
+        }
+        if self.playing.iter().any(|(playing, _)| *playing == register) {
+            self.stop_playing();
+            return Err(format!("Macro {register} plays itself, playback stopped."));
+        }
+        if keys.len().saturating_mul(count).saturating_add(pending) > MAX_PENDING_KEYS {
+            self.stop_playing();
+            return Err(format!("Macro {register} is too long to play {count} times."));
+        }
+        self.playing.push((register, pending));
+        for _ in 0..count {
+            for key in keys.iter().rev() {
+                self.pending.push_front(*key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next_key(&mut self) -> Option<Key> {
+        let key = self.pending.pop_front();
+        // Keys typed next don't belong to any macro
+        if key.is_none() {
+            self.playing.clear();
+        }
+        key
+    }
+
+    fn stop_playing(&mut self) {
+        self.pending.clear();
+        self.playing.clear();
+    }
+}
+
+pub fn is_register(c: char) -> bool {
+    c.is_ascii_alphabetic()
+}
@@ -13,18 +13,48 @@ mod document;
 mod row;
 mod highlighting;
 mod filetype;
+mod encoding;
+mod config;
+mod recovery;
+mod indentation;
+mod vi;
+mod keymap;
+mod prompt;
+mod macros;
+mod scripts;
 
 pub use document::Document;
+pub use document::SortKey;
+pub use document::SortOptions;
 
 pub use editor::Editor;
 pub use editor::Position;
 pub use editor::SearchDirection;
+pub use editor::SearchOptions;
 
 pub use filetype::FileType;
 pub use filetype::HighlightingOptions;
 
 pub use row::Row;
 
+pub use encoding::FileEncoding;
+
+pub use config::Config;
+
+pub use recovery::Recovery;
+
+pub use indentation::Indentation;
+
+pub use vi::Vi;
+
+pub use keymap::Keymap;
+
+pub use prompt::Prompt;
+
+pub use macros::Macros;
+
+pub use scripts::Scripts;
+
 pub use terminal::Terminal;
 
 fn main() {
@@ -0,0 +1,108 @@
+use termion::event::Key;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// A single line being edited in the message bar. The cursor is a grapheme index
+#[derive(Default)]
+pub struct Prompt {
+    text: String,
+    cursor: usize,
+    // Text removed by the last kill, for Ctrl-Y
+    killed: String,
+}
+
+impl Prompt {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.to_owned();
+        self.cursor = self.len();
+    }
+
+    // Screen columns before the cursor
+    pub fn column(&self) -> usize {
+        self.text[..self.byte_index(self.cursor)].width()
+    }
+
+    // Returns false for keys left to the caller
+    pub fn handle(&mut self, key: Key) -> bool {
+        match key {
+            Key::Char(c) if !c.is_control() => {
+                let index = self.byte_index(self.cursor);
+                self.text.insert(index, c);
+                // A combining character joins the grapheme before it
+                self.cursor = self.text[..index.saturating_add(c.len_utf8())].graphemes(true).count();
+            },
+            Key::Backspace | Key::Ctrl('h') => self.remove(self.cursor.saturating_sub(1), self.cursor, false),
+            Key::Delete | Key::Ctrl('d') => self.remove(self.cursor, self.cursor.saturating_add(1), false),
+            Key::Left | Key::Ctrl('b') => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right | Key::Ctrl('f') => self.cursor = std::cmp::min(self.cursor.saturating_add(1), self.len()),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.len(),
+            Key::Alt('b') => self.cursor = self.word_start(),
+            Key::Alt('f') => self.cursor = self.word_end(),
+            Key::Ctrl('w') => self.remove(self.word_start(), self.cursor, true),
+            Key::Alt('d') => self.remove(self.cursor, self.word_end(), true),
+            Key::Ctrl('u') => self.remove(0, self.cursor, true),
+            Key::Ctrl('k') => self.remove(self.cursor, self.len(), true),
+            Key::Ctrl('y') => {
+                let index = self.byte_index(self.cursor);
+                self.text.insert_str(index, &self.killed);
+                self.cursor = self.text[..index.saturating_add(self.killed.len())].graphemes(true).count();
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.text.graphemes(true).count()
+    }
+
+    fn byte_index(&self, grapheme: usize) -> usize {
+        self.text.grapheme_indices(true).nth(grapheme).map_or(self.text.len(), |(index, _)| index)
+    }
+
+    fn remove(&mut self, start: usize, end: usize, kill: bool) {
+        let range = self.byte_index(start)..self.byte_index(end);
+        if range.is_empty() {
+            return;
+        }
+        let removed: String = self.text.drain(range).collect();
+        if kill {
+            self.killed = removed;
+        }
+        self.cursor = start;
+    }
+
+    fn is_word(grapheme: &str) -> bool {
+        grapheme.chars().any(|c| c.is_alphanumeric() || c == '_')
+    }
+
+    // Start of the word before the cursor, skipping anything else first
+    fn word_start(&self) -> usize {
+        let graphemes: Vec<&str> = self.text.graphemes(true).take(self.cursor).collect();
+        let mut index = graphemes.len();
+        while index > 0 && !Self::is_word(graphemes[index - 1]) {
+            index -= 1;
+        }
+        while index > 0 && Self::is_word(graphemes[index - 1]) {
+            index -= 1;
+        }
+        index
+    }
+
+    fn word_end(&self) -> usize {
+        let graphemes: Vec<&str> = self.text.graphemes(true).collect();
+        let mut index = self.cursor;
+        while index < graphemes.len() && !Self::is_word(graphemes[index]) {
+            index += 1;
+        }
+        while index < graphemes.len() && Self::is_word(graphemes[index]) {
+            index += 1;
+        }
+        index
+    }
+}
@@ -0,0 +1,77 @@
+use std::env;
+use std::fs;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{Error, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+
+const HEADER: &str = "hecto recovery file for ";
+
+pub struct Recovery {
+    path: PathBuf,
+}
+
+impl Recovery {
+    pub fn for_file(filename: &str) -> Option<Self> {
+        let absolute = fs::canonicalize(filename)
+            .or_else(|_| env::current_dir().map(|dir| dir.join(filename)))
+            .ok()?;
+        // Percent-encoded, so that no two paths share a name
+        let name = absolute.to_string_lossy().replace('%', "%25").replace('/', "%2F");
+        Some(Self {
+            path: Self::dir()?.join(format!("{name}.swp")),
+        })
+    }
+
+    fn dir() -> Option<PathBuf> {
+        if let Some(dir) = env::var_os("XDG_STATE_HOME") {
+            return Some(PathBuf::from(dir).join("hecto"));
+        }
+        env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state").join("hecto"))
+    }
+
+    // Unsaved content may be private, only the user can read it
+    pub fn write(&self, filename: &str, content: &str) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        // Written aside first so a crash while writing can't destroy the previous journal
+        let tmp_path = self.path.with_extension("swp.tmp");
+        let _ = fs::remove_file(&tmp_path);
+        let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp_path)?;
+        file.write_all(format!("{HEADER}{filename}\n{content}").as_bytes())?;
+        fs::rename(tmp_path, &self.path)
+    }
+
+    pub fn read(&self) -> Option<String> {
+        let data = fs::read_to_string(&self.path).ok()?;
+        let (header, content) = data.split_once('\n')?;
+        if !header.starts_with(HEADER) {
+            return None;
+        }
+        Some(content.to_owned())
+    }
+
+    pub fn remove(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Line ranges (1-based) that differ between the two texts, compared line by line
+pub fn changed_lines(original: &str, recovered: &str) -> Vec<(usize, usize)> {
+    let original: Vec<_> = original.lines().collect();
+    let recovered: Vec<_> = recovered.lines().collect();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+
+    for index in 0..original.len().max(recovered.len()) {
+        if original.get(index) == recovered.get(index) {
+            continue;
+        }
+        let line = index.saturating_add(1);
+        match ranges.last_mut() {
+            Some((_, end)) if end.saturating_add(1) == line => *end = line,
+            _ => ranges.push((line, line)),
+        }
+    }
+    ranges
+}
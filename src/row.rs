@@ -1,9 +1,12 @@
 use std::cmp;
+use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
-use termion::color;
+use unicode_width::UnicodeWidthStr;
+use termion::{color, style};
 
 use crate::HighlightingOptions;
 use crate::SearchDirection;
+use crate::SearchOptions;
 use crate::highlighting;
 
 #[derive(Default)]
@@ -14,41 +17,126 @@ pub struct Row {
 }
 
 impl Row {
-    pub fn render(&self, start: usize, end: usize) -> String {
-        let end = cmp::min(end, self.string.len());
-        let start = cmp::min(start, end);
-        
+    // `start` and `end` are screen columns, tabs expand to the next tab stop.
+    // `selection` holds the indices of the selected graphemes, `cursors` those
+    // drawn as extra cursors
+    pub fn render(&self, start: usize, end: usize, tab_width: usize, selection: &Range<usize>, cursors: &[usize]) -> String {
         let mut current_highlighting = &highlighting::Type::None;
         let mut result = format!("{}", color::Fg(current_highlighting.to_color()));
-        for (index, grapheme) in self.string[..]
-            .graphemes(true)
-            .enumerate()
-            .skip(start)
-            .take(end-start) {
-                if let Some(c) = grapheme.chars().next() {
-                    let highlighting_type = self
-                        .highlighting
-                        .get(index)
-                        .unwrap_or(&highlighting::Type::None);
-
-                    if highlighting_type != current_highlighting {
-                        let start_highlighting = format!("{}", color::Fg(highlighting_type.to_color()));
-                        result.push_str(&start_highlighting);
-                        current_highlighting = highlighting_type;
-                    }
+        let mut current_selected = false;
+        let mut column = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            if column >= end {
+                break;
+            }
+            let next_column = column.saturating_add(grapheme_width(grapheme, column, tab_width));
+            if next_column > start {
+                let highlighting_type = self
+                    .highlighting
+                    .get(index)
+                    .unwrap_or(&highlighting::Type::None);
+
+                if highlighting_type != current_highlighting {
+                    let start_highlighting = format!("{}", color::Fg(highlighting_type.to_color()));
+                    result.push_str(&start_highlighting);
+                    current_highlighting = highlighting_type;
+                }
 
-                    if c == '\t' {
-                        result.push_str(" ");
+                if selection.contains(&index) != current_selected {
+                    current_selected = !current_selected;
+                    if current_selected {
+                        result.push_str(&format!("{}", color::Bg(highlighting::SELECTION_BG_COLOR)));
                     } else {
-                        result.push(c);
+                        result.push_str(&format!("{}", color::Bg(color::Reset)));
                     }
                 }
+
+                let is_cursor = cursors.contains(&index);
+                if is_cursor {
+                    result.push_str(&format!("{}", style::Invert));
+                }
+                // Only the visible part of a tab or wide grapheme that straddles the viewport
+                let visible = cmp::min(next_column, end).saturating_sub(cmp::max(column, start));
+                if grapheme == "\t" {
+                    result.push_str(&" ".repeat(visible));
+                } else if column < start {
+                    result.push_str(&"<".repeat(visible));
+                } else if next_column > end {
+                    result.push_str(&">".repeat(visible));
+                } else if grapheme.chars().all(char::is_control) {
+                    result.push('?');
+                } else {
+                    result.push_str(grapheme);
+                }
+                if is_cursor {
+                    result.push_str(&format!("{}", style::NoInvert));
+                }
             }
-        let end_highlighting = format!("{}", color::Fg(color::Reset));
+            column = next_column;
+        }
+        if cursors.contains(&self.len()) && (start..end).contains(&column) {
+            result.push_str(&format!("{}{} {}", color::Bg(color::Reset), style::Invert, style::NoInvert));
+        }
+        let end_highlighting = format!("{}{}", color::Fg(color::Reset), color::Bg(color::Reset));
         result.push_str(&end_highlighting);
         result
     }
 
+    pub fn x_to_column(&self, x: usize, tab_width: usize) -> usize {
+        self.string[..]
+            .graphemes(true)
+            .take(x)
+            .fold(0, |column, grapheme| column.saturating_add(grapheme_width(grapheme, column, tab_width)))
+    }
+
+    pub fn column_to_x(&self, column: usize, tab_width: usize) -> usize {
+        let mut current_column: usize = 0;
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            current_column = current_column.saturating_add(grapheme_width(grapheme, current_column, tab_width));
+            if current_column > column {
+                return index;
+            }
+        }
+        self.len
+    }
+
+    // Grapheme indices where each screen line of the wrapped row starts
+    pub fn wrap_points(&self, width: usize, tab_width: usize, at_words: bool) -> Vec<usize> {
+        let mut points = vec![0];
+        let mut line_start_column: usize = 0;
+        let mut word_start = None;
+        let mut previous_whitespace = false;
+        let mut column: usize = 0;
+
+        for (index, grapheme) in self.string[..].graphemes(true).enumerate() {
+            let next_column = column.saturating_add(grapheme_width(grapheme, column, tab_width));
+            let whitespace = grapheme.chars().all(char::is_whitespace);
+            if at_words && previous_whitespace && !whitespace {
+                word_start = Some((index, column));
+            }
+
+            let line_start = points.last().copied().unwrap_or(0);
+            if next_column.saturating_sub(line_start_column) > width && index > line_start {
+                // The whole word moves to the next line, unless it is the only one on this line
+                let (break_index, break_column) = match word_start {
+                    Some((word_index, word_column)) if word_index > line_start => (word_index, word_column),
+                    _ => (index, column),
+                };
+                points.push(break_index);
+                line_start_column = break_column;
+                if next_column.saturating_sub(line_start_column) > width && index > break_index {
+                    points.push(index);
+                    line_start_column = column;
+                }
+                word_start = None;
+            }
+
+            previous_whitespace = whitespace;
+            column = next_column;
+        }
+        points
+    }
+
     pub fn insert(&mut self, at: usize, c: char) {
         if at >= self.len() {
             self.string.push(c);
@@ -122,61 +210,42 @@ impl Row {
         }
     }
 
-    pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
+    pub fn find(&self, query: &str, at: usize, direction: SearchDirection, options: &SearchOptions) -> Option<usize> {
         if at > self.len || query.is_empty() {
             return None;
         }
 
-        let start = match direction {
-            SearchDirection::Forward => at,
-            SearchDirection::Backward => 0,
-        };
-        let end = match direction {
-            SearchDirection::Forward => self.len,
-            SearchDirection::Backward => at,
-        };
-        
+        let search = Search::new(&self.string, query, options);
+        match direction {
+            SearchDirection::Forward => (at..self.len).find(|index| search.matches_at(*index)),
+            SearchDirection::Backward => (0..at).rev().find(|index| search.matches_at(*index)),
+        }
+    }
 
-        let substring: String = self
-            .string
-            .graphemes(true)
-            .skip(start)
-            .take(end-start)
-            .collect();
-
-        let matching_byte_index = match direction {
-            SearchDirection::Forward => substring.find(query),
-            SearchDirection::Backward => substring.rfind(query)
-        };
-        if let Some(matching_byte_index) = matching_byte_index {
-            for (grapheme_index, (byte_index, _)) in 
-                substring.grapheme_indices(true).enumerate() {
-                    if matching_byte_index == byte_index {
-                        return Some(start + grapheme_index);
-                    }
+    // Starts of the matches, not overlapping each other, found in one pass
+    pub fn find_all(&self, query: &str, options: &SearchOptions) -> Vec<usize> {
+        let mut matches = Vec::new();
+        if query.is_empty() {
+            return matches;
+        }
+        let search = Search::new(&self.string, query, options);
+        let mut index = 0;
+        while index < self.len {
+            if search.matches_at(index) {
+                matches.push(index);
+                index = index.saturating_add(search.query.len());
+            } else {
+                index = index.saturating_add(1);
             }
         }
-
-        None
+        matches
     }
 
-    pub fn highlight(&mut self, opts: &HighlightingOptions, word: Option<&str>) {
+    // `search` holds the starts of the matches found by `find_all` and their
+    // length, both in graphemes
+    pub fn highlight(&mut self, opts: &HighlightingOptions, search: Option<(&[usize], usize)>) {
         let mut highlightings = Vec::new();
         let chars: Vec<_> = self.string.chars().collect();
-        let mut matches = Vec::new();
-        let mut search_index = 0;
-
-        // Search matches finding
-        if let Some(word) = word {
-            while let Some(search_match) = self.find(word, search_index, SearchDirection::Forward) {
-                matches.push(search_match);
-                if let Some(next_index) = search_match.checked_add(word[..].graphemes(true).count()) {
-                    search_index = next_index;
-                } else {
-                    break;
-                }
-            }
-        }
 
         let mut index = 0;
         let mut in_string = false;
@@ -186,9 +255,9 @@ impl Row {
             prev_highlighting = highlightings.get(index.saturating_sub(1)).unwrap_or(&highlighting::Type::None);
 
             // Search results highlighting
-            if let Some(word) = word {
+            if let Some((matches, len)) = search {
                 if matches.contains(&index) {
-                    for _ in word[..].graphemes(true) {
+                    for _ in 0..len {
                         index += 1;
                         highlightings.push(highlighting::Type::SearchMatch);
                     }
@@ -233,15 +302,13 @@ impl Row {
             }
 
             // Single line comment highlighting
-            if *c == '/' {
-                if let Some(comment_char) = chars.get(index.saturating_add(1)) {
-                    if *comment_char == '/' {
-                        // Rest of the line is a char
-                        for _ in index..chars.len() {
-                            highlightings.push(highlighting::Type::Comment);
-                        }
-                        break;
+            if let (true, Some(token)) = (opts.comments(), opts.line_comment()) {
+                if token.chars().enumerate().all(|(offset, token_char)| chars.get(index.saturating_add(offset)) == Some(&token_char)) {
+                    // Rest of the line is a comment
+                    for _ in index..chars.len() {
+                        highlightings.push(highlighting::Type::Comment);
                     }
+                    break;
                 }
             }
 
@@ -351,6 +418,83 @@ impl Row {
         self.string.as_bytes()
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
+    pub fn grapheme(&self, index: usize) -> Option<&str> {
+        self.string[..].graphemes(true).nth(index)
+    }
+
+    // Brackets in strings and comments don't count when matching pairs
+    pub fn is_code(&self, index: usize) -> bool {
+        !matches!(
+            self.highlighting.get(index),
+            Some(highlighting::Type::String | highlighting::Type::Character | highlighting::Type::Comment)
+        )
+    }
+
+    pub fn highlight_bracket(&mut self, index: usize) {
+        if let Some(highlighting_type) = self.highlighting.get_mut(index) {
+            *highlighting_type = highlighting::Type::MatchingBracket;
+        }
+    }
+
+    pub fn leading_whitespace(&self) -> &str {
+        let content = self.string.trim_start_matches([' ', '\t']);
+        &self.string[..self.string.len().saturating_sub(content.len())]
+    }
+
+}
+
+// Compared grapheme by grapheme, so folding case can't shift the indices.
+// Graphemes are folded as they are compared rather than copied
+struct Search<'a> {
+    graphemes: Vec<&'a str>,
+    query: Vec<&'a str>,
+    ignore_case: bool,
+    whole_word: bool,
+}
+
+impl<'a> Search<'a> {
+    fn new(text: &'a str, query: &'a str, options: &SearchOptions) -> Self {
+        Self {
+            graphemes: text.graphemes(true).collect(),
+            query: query.graphemes(true).collect(),
+            ignore_case: options.ignores_case(query),
+            whole_word: options.whole_word,
+        }
+    }
+
+    fn matches_at(&self, index: usize) -> bool {
+        let end = index.saturating_add(self.query.len());
+        end <= self.graphemes.len()
+            && self.graphemes[index..end].iter().zip(&self.query).all(|(grapheme, wanted)| self.same(grapheme, wanted))
+            && (!self.whole_word || ((index == 0 || !self.is_word(index - 1)) && !self.is_word(end)))
+    }
+
+    fn same(&self, grapheme: &str, wanted: &str) -> bool {
+        if self.ignore_case {
+            grapheme.chars().flat_map(char::to_lowercase).eq(wanted.chars().flat_map(char::to_lowercase))
+        } else {
+            grapheme == wanted
+        }
+    }
+
+    fn is_word(&self, index: usize) -> bool {
+        self.graphemes
+            .get(index)
+            .is_some_and(|grapheme| grapheme.chars().any(|c| c.is_alphanumeric() || c == '_'))
+    }
+}
+
+// CJK characters and emoji take two cells, anything else one
+fn grapheme_width(grapheme: &str, column: usize, tab_width: usize) -> usize {
+    if grapheme == "\t" {
+        tab_width.saturating_sub(column % tab_width)
+    } else {
+        grapheme.width().clamp(1, 2)
+    }
 }
 
 impl From<&str> for Row {
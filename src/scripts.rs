@@ -0,0 +1,198 @@
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST, INT};
+
+use crate::keymap::Action;
+use crate::Config;
+use crate::Document;
+use crate::Position;
+use crate::Row;
+
+const SCRIPTS_DIR: &str = "scripts";
+// A script running longer, e.g. stuck in a loop, fails instead of hanging the editor
+const MAX_OPERATIONS: u64 = 10_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+
+// What a script asks the editor to do once it returns
+pub enum Request {
+    Message(String),
+    Run(Action),
+    // Asks with the text, then calls the function with the answer
+    Prompt(String, String),
+    // Binds the keys to the function
+    Bind(String, String),
+}
+
+// The document and cursor are lent to the scripts while they run
+#[derive(Default)]
+struct Host {
+    document: Document,
+    cursor: Position,
+    requests: Vec<Request>,
+}
+
+pub struct Scripts {
+    engine: Engine,
+    ast: AST,
+    host: Rc<RefCell<Host>>,
+}
+
+impl Scripts {
+    // Compiles the `.rhai` files of the scripts directory, in name order
+    pub fn load() -> (Self, Vec<String>) {
+        let host = Rc::new(RefCell::new(Host::default()));
+        let mut scripts = Self {
+            engine: engine(&host),
+            ast: AST::empty(),
+            host,
+        };
+        let mut errors = Vec::new();
+
+        let mut paths: Vec<_> = Config::dir()
+            .and_then(|dir| fs::read_dir(dir.join(SCRIPTS_DIR)).ok())
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "rhai"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            match scripts.engine.compile_file(path.clone()) {
+                Ok(ast) => {
+                    scripts.ast.combine(ast);
+                },
+                Err(error) => errors.push(format!("script {}: {error}", path.display())),
+            }
+        }
+
+        (scripts, errors)
+    }
+
+    // Runs the top level statements of the scripts, once at startup
+    pub fn run(&mut self, document: &mut Document, cursor: &mut Position) -> Result<Vec<Request>, String> {
+        self.lend(document, cursor, |engine, ast| engine.run_ast_with_scope(&mut Scope::new(), ast))
+    }
+
+    pub fn call(&mut self, document: &mut Document, cursor: &mut Position, function: &str, args: Vec<Dynamic>) -> Result<Vec<Request>, String> {
+        self.lend(document, cursor, |engine, ast| {
+            let options = CallFnOptions::new().eval_ast(false);
+            engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), ast, function, args).map(|_| ())
+        })
+    }
+
+    fn lend<R>(
+        &mut self,
+        document: &mut Document,
+        cursor: &mut Position,
+        run: impl FnOnce(&Engine, &AST) -> Result<R, Box<EvalAltResult>>,
+    ) -> Result<Vec<Request>, String> {
+        {
+            let mut host = self.host.borrow_mut();
+            std::mem::swap(&mut host.document, document);
+            std::mem::swap(&mut host.cursor, cursor);
+        }
+        let result = run(&self.engine, &self.ast);
+        let mut host = self.host.borrow_mut();
+        std::mem::swap(&mut host.document, document);
+        std::mem::swap(&mut host.cursor, cursor);
+        let requests = std::mem::take(&mut host.requests);
+        result.map(|_| requests).map_err(|error| error.to_string())
+    }
+
+    // Functions without parameters, run as commands
+    pub fn commands(&self) -> Vec<String> {
+        self.ast
+            .iter_functions()
+            .filter(|function| function.params.is_empty())
+            .map(|function| function.name.to_owned())
+            .collect()
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|function| function.name == name && function.params.is_empty())
+    }
+}
+
+// Rows and columns are counted from 0, columns in graphemes
+fn engine(host: &Rc<RefCell<Host>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(MAX_CALL_LEVELS);
+    let index = |value: INT| usize::try_from(value).unwrap_or(0);
+
+    let state = Rc::clone(host);
+    engine.register_fn("line_count", move || INT::try_from(state.borrow().document.len()).unwrap_or(INT::MAX));
+    let state = Rc::clone(host);
+    engine.register_fn("line", move |y: INT| {
+        state.borrow().document.row(index(y)).map_or_else(String::new, |row| row.as_str().to_owned())
+    });
+    let state = Rc::clone(host);
+    engine.register_fn("filename", move || state.borrow().document.filename.clone().unwrap_or_default());
+    let state = Rc::clone(host);
+    engine.register_fn("insert", move |y: INT, x: INT, text: &str| -> Result<(), Box<EvalAltResult>> {
+        let mut host = state.borrow_mut();
+        let at = position(&host.document, y, x)?;
+        host.document.insert_text(&at, text);
+        Ok(())
+    });
+    let state = Rc::clone(host);
+    engine.register_fn("delete", move |y: INT, x: INT, end_y: INT, end_x: INT| -> Result<(), Box<EvalAltResult>> {
+        let mut host = state.borrow_mut();
+        let (start, end) = (position(&host.document, y, x)?, position(&host.document, end_y, end_x)?);
+        host.document.delete_range(&start, &end);
+        Ok(())
+    });
+    let state = Rc::clone(host);
+    engine.register_fn("text", move |y: INT, x: INT, end_y: INT, end_x: INT| -> Result<String, Box<EvalAltResult>> {
+        let host = state.borrow();
+        let (start, end) = (position(&host.document, y, x)?, position(&host.document, end_y, end_x)?);
+        Ok(host.document.text(&start, &end))
+    });
+
+    let state = Rc::clone(host);
+    engine.register_fn("cursor_x", move || INT::try_from(state.borrow().cursor.x).unwrap_or(INT::MAX));
+    let state = Rc::clone(host);
+    engine.register_fn("cursor_y", move || INT::try_from(state.borrow().cursor.y).unwrap_or(INT::MAX));
+    let state = Rc::clone(host);
+    engine.register_fn("move_cursor", move |y: INT, x: INT| {
+        state.borrow_mut().cursor = Position { x: index(x), y: index(y) };
+    });
+
+    // The terminal is in raw mode, printed text goes to the message bar
+    let state = Rc::clone(host);
+    engine.on_print(move |text| state.borrow_mut().requests.push(Request::Message(text.to_owned())));
+    let state = Rc::clone(host);
+    engine.on_debug(move |text, _, _| state.borrow_mut().requests.push(Request::Message(text.to_owned())));
+    let state = Rc::clone(host);
+    engine.register_fn("message", move |text: &str| {
+        state.borrow_mut().requests.push(Request::Message(text.to_owned()));
+    });
+    let state = Rc::clone(host);
+    engine.register_fn("prompt", move |text: &str, function: &str| {
+        state.borrow_mut().requests.push(Request::Prompt(text.to_owned(), function.to_owned()));
+    });
+    let state = Rc::clone(host);
+    engine.register_fn("bind", move |keys: &str, function: &str| {
+        state.borrow_mut().requests.push(Request::Bind(keys.to_owned(), function.to_owned()));
+    });
+    let state = Rc::clone(host);
+    engine.register_fn("run", move |name: &str| -> Result<(), Box<EvalAltResult>> {
+        let action = Action::from_name(name).ok_or_else(|| format!("unknown command `{name}`"))?;
+        state.borrow_mut().requests.push(Request::Run(action));
+        Ok(())
+    });
+
+    engine
+}
+
+// Positions outside the document are errors rather than moved into it. The
+// start of the row past the last one is allowed, to append
+fn position(document: &Document, y: INT, x: INT) -> Result<Position, Box<EvalAltResult>> {
+    match (usize::try_from(y), usize::try_from(x)) {
+        (Ok(y), Ok(x)) if y < document.len() && x <= document.row(y).map_or(0, Row::len) => Ok(Position { x, y }),
+        (Ok(y), Ok(0)) if y == document.len() => Ok(Position { x: 0, y }),
+        _ => Err(format!("position ({y}, {x}) is outside the document").into()),
+    }
+}
@@ -1,7 +1,11 @@
-use std::io::{self, stdout, Write};
-
-use termion::event::Key;
-use termion::input::TermRead;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, stdout, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use termion::event::{self, Event, Key};
+use termion::input::MouseTerminal;
 use termion::raw::{IntoRawMode, RawTerminal};
 use termion::color;
 use termion::cursor;
@@ -15,9 +19,18 @@ pub struct Size {
 
 pub struct Terminal {
     size: Size, 
-    _stdout: RawTerminal<std::io::Stdout>,
+    stdout: MouseTerminal<RawTerminal<std::io::Stdout>>,
+    tty: File,
+    // Bytes read but not parsed yet
+    input: VecDeque<u8>,
 }
 
+// Those `MouseTerminal` writes when created and dropped
+const ENTER_MOUSE_SEQUENCE: &str = "\x1B[?1000h\x1B[?1002h\x1B[?1015h\x1B[?1006h";
+const EXIT_MOUSE_SEQUENCE: &str = "\x1B[?1006l\x1B[?1015l\x1B[?1002l\x1B[?1000l";
+// The rest of an escape sequence split across reads is waited for this long
+const SEQUENCE_TIMEOUT: Duration = Duration::from_millis(50);
+
 impl Terminal {
     pub fn default() -> Result<Self, std::io::Error> {
         let size = termion::terminal_size()?;
@@ -26,7 +39,9 @@ impl Terminal {
                 width: size.0,
                 height: size.1.saturating_sub(2), // For the two status lines
             },
-            _stdout: stdout().into_raw_mode()?,
+            stdout: MouseTerminal::from(stdout().into_raw_mode()?),
+            tty: termion::get_tty()?,
+            input: VecDeque::new(),
         })
     }
 
@@ -73,10 +88,48 @@ impl Terminal {
         io::stdout().flush()
     }
 
-    pub fn read_key() -> Result<Key, std::io::Error> {
+    // Mouse reporting goes too, or clicks would reach a helper command as text
+    pub fn suspend_raw_mode(&mut self) -> Result<(), io::Error> {
+        write!(self.stdout, "{EXIT_MOUSE_SEQUENCE}")?;
+        self.stdout.flush()?;
+        self.stdout.suspend_raw_mode()
+    }
+
+    pub fn activate_raw_mode(&mut self) -> Result<(), io::Error> {
+        self.stdout.activate_raw_mode()?;
+        write!(self.stdout, "{ENTER_MOUSE_SEQUENCE}")?;
+        self.stdout.flush()
+    }
+
+    // Waits for an event up to the timeout, or for ever without one
+    pub fn read_event(&mut self, timeout: Option<Duration>) -> Result<Option<Event>, std::io::Error> {
+        while self.input.is_empty() {
+            if !fill(&self.tty, &mut self.input, timeout)? && timeout.is_some() {
+                return Ok(None);
+            }
+        }
+        let Some(first) = self.input.pop_front() else {
+            return Ok(None);
+        };
+        // An escape sequence arrives at once, a lone Esc is the key
+        if first == b'\x1B' && self.input.is_empty() {
+            return Ok(Some(Event::Key(Key::Esc)));
+        }
+        let (tty, input) = (&self.tty, &mut self.input);
+        let mut bytes = std::iter::from_fn(|| {
+            if input.is_empty() && !fill(tty, input, Some(SEQUENCE_TIMEOUT)).ok()? {
+                return None;
+            }
+            input.pop_front().map(Ok)
+        });
+        event::parse_event(first, &mut bytes).map(Some)
+    }
+
+    // Skips mouse events
+    pub fn read_key(&mut self) -> Result<Key, std::io::Error> {
         loop {
-            if let Some(key) = io::stdin().lock().keys().next() {
-                return key;
+            if let Some(Event::Key(key)) = self.read_event(None)? {
+                return Ok(key);
             }
         }
     }
@@ -84,4 +137,33 @@ impl Terminal {
     pub fn size(&self) -> &Size {
         &self.size
     }
-}
\ No newline at end of file
+}
+
+// Waits for input up to the timeout, or for ever without one, and adds it to
+// the buffer. Returns whether there was any
+fn fill(mut tty: &File, input: &mut VecDeque<u8>, timeout: Option<Duration>) -> Result<bool, io::Error> {
+    let mut poll_fd = libc::pollfd {
+        fd: tty.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = timeout.map_or(-1, |timeout| i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX));
+    // SAFETY: `poll_fd` is a single valid `pollfd`, as the count says
+    let ready = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+    if ready < 0 {
+        let error = io::Error::last_os_error();
+        // E.g. on a resize signal
+        return if error.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(error) };
+    }
+    if ready == 0 {
+        return Ok(false);
+    }
+
+    let mut buffer = [0; 1024];
+    let read = tty.read(&mut buffer)?;
+    if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the terminal closed"));
+    }
+    input.extend(&buffer[..read]);
+    Ok(true)
+}
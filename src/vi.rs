@@ -0,0 +1,320 @@
+use termion::event::Key;
+
+use crate::Document;
+use crate::Position;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Normal,
+    Insert,
+    Visual,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "NORMAL",
+            Self::Insert => "INSERT",
+            Self::Visual => "VISUAL",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordStart,
+    WordBack,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FileStart,
+    FileEnd,
+}
+
+impl Motion {
+    // Operators act on whole rows with these
+    pub fn is_linewise(self) -> bool {
+        matches!(self, Self::Up | Self::Down | Self::FileStart | Self::FileEnd)
+    }
+
+    // The character these land on is part of the operated text
+    pub fn is_inclusive(self) -> bool {
+        matches!(self, Self::WordEnd | Self::LineEnd)
+    }
+}
+
+pub enum Command {
+    Move(Motion),
+    // `d`, `c` or `y` followed by a motion
+    Operate(char, Motion),
+    // `dd`, `cc` and `yy`
+    OperateRows(char),
+    // `d`, `c` or `y` on the visual selection
+    OperateSelection(char),
+    // `i`, `a`, `I`, `A`, `o` or `O`
+    Insert(char),
+    DeleteChars,
+    Paste(bool),
+    Undo,
+    Visual,
+    Repeat,
+    Ex,
+}
+
+#[derive(Default)]
+pub struct Vi {
+    pub mode: Mode,
+    count: Option<usize>,
+    operator: Option<char>,
+    pending_g: bool,
+    // Keys of the command being typed, then of the last complete command
+    keys: Vec<Key>,
+    command: Vec<Key>,
+    // Keys of the change in progress and of the last one, for `.`
+    change: Option<Vec<Key>>,
+    last_change: Vec<Key>,
+    pub repeating: bool,
+    pub register: String,
+    pub linewise: bool,
+}
+
+impl Vi {
+    // Returns the command and its count once the keys typed so far form one
+    pub fn parse(&mut self, c: char) -> Option<(Command, Option<usize>)> {
+        self.keys.push(Key::Char(c));
+        let motion = match c {
+            'h' => Some(Motion::Left),
+            'l' | ' ' => Some(Motion::Right),
+            'k' => Some(Motion::Up),
+            'j' | '\n' => Some(Motion::Down),
+            'w' => Some(Motion::WordStart),
+            'b' => Some(Motion::WordBack),
+            'e' => Some(Motion::WordEnd),
+            '0' if self.count.is_none() => Some(Motion::LineStart),
+            '$' => Some(Motion::LineEnd),
+            'g' if self.pending_g => Some(Motion::FileStart),
+            'G' => Some(Motion::FileEnd),
+            _ => None,
+        };
+
+        let command = match (c, motion) {
+            (_, Some(motion)) => match self.operator {
+                Some(operator) => Command::Operate(operator, motion),
+                None => Command::Move(motion),
+            },
+            (_, None) if self.pending_g => {
+                self.reset();
+                return None;
+            },
+            ('0'..='9', None) => {
+                let digit = c.to_digit(10).unwrap_or(0) as usize;
+                self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                return None;
+            },
+            ('g', None) => {
+                self.pending_g = true;
+                return None;
+            },
+            ('d' | 'c' | 'y', None) if self.mode == Mode::Visual => Command::OperateSelection(c),
+            ('x', None) if self.mode == Mode::Visual => Command::OperateSelection('d'),
+            ('d' | 'c' | 'y', None) => match self.operator {
+                Some(operator) if operator == c => Command::OperateRows(c),
+                Some(_) => {
+                    self.reset();
+                    return None;
+                },
+                None => {
+                    self.operator = Some(c);
+                    return None;
+                },
+            },
+            (_, None) if self.operator.is_some() => {
+                self.reset();
+                return None;
+            },
+            ('i' | 'a' | 'I' | 'A' | 'o' | 'O', None) if self.mode == Mode::Normal => Command::Insert(c),
+            ('x', None) => Command::DeleteChars,
+            ('p', None) => Command::Paste(true),
+            ('P', None) => Command::Paste(false),
+            ('u', None) => Command::Undo,
+            ('v', None) => Command::Visual,
+            ('.', None) => Command::Repeat,
+            (':', None) => Command::Ex,
+            _ => {
+                self.reset();
+                return None;
+            },
+        };
+
+        let count = self.count;
+        self.command = std::mem::take(&mut self.keys);
+        self.reset();
+        Some((command, count))
+    }
+
+    // Drops a partly typed command
+    pub fn reset(&mut self) {
+        self.count = None;
+        self.operator = None;
+        self.pending_g = false;
+        self.keys.clear();
+    }
+
+    // The command just parsed changes the text and can be repeated
+    pub fn begin_change(&mut self) {
+        if !self.repeating {
+            self.change = Some(self.command.clone());
+        }
+    }
+
+    // Keys typed in insert mode are part of the change that started it
+    pub fn record(&mut self, key: Key) {
+        if let Some(change) = self.change.as_mut() {
+            change.push(key);
+        }
+    }
+
+    pub fn end_change(&mut self) {
+        if let Some(change) = self.change.take() {
+            self.last_change = change;
+        }
+    }
+
+    pub fn last_change(&self) -> Vec<Key> {
+        self.last_change.clone()
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum Class {
+    Blank,
+    EmptyRow,
+    Word,
+    Punctuation,
+}
+
+// The end of a row counts as blank, an empty row as a word of its own
+fn class(document: &Document, at: &Position) -> Class {
+    let Some(row) = document.row(at.y) else {
+        return Class::Blank;
+    };
+    if row.is_empty() {
+        return Class::EmptyRow;
+    }
+    match row.grapheme(at.x).and_then(|grapheme| grapheme.chars().next()) {
+        Some(c) if c.is_alphanumeric() || c == '_' => Class::Word,
+        Some(c) if !c.is_whitespace() => Class::Punctuation,
+        _ => Class::Blank,
+    }
+}
+
+fn next(document: &Document, at: &Position) -> Option<Position> {
+    let len = document.row(at.y)?.len();
+    if at.x < len {
+        Some(Position { x: at.x.saturating_add(1), y: at.y })
+    } else if at.y.saturating_add(1) < document.len() {
+        Some(Position { x: 0, y: at.y.saturating_add(1) })
+    } else {
+        None
+    }
+}
+
+fn previous(document: &Document, at: &Position) -> Option<Position> {
+    if at.x > 0 {
+        Some(Position { x: at.x.saturating_sub(1), y: at.y })
+    } else {
+        let y = at.y.checked_sub(1)?;
+        Some(Position { x: document.row(y)?.len(), y })
+    }
+}
+
+fn word_start(document: &Document, at: &Position) -> Option<Position> {
+    let start = class(document, at);
+    let mut at = next(document, at)?;
+    if start != Class::Blank && start != Class::EmptyRow {
+        while class(document, &at) == start {
+            at = next(document, &at)?;
+        }
+    }
+    while class(document, &at) == Class::Blank {
+        at = next(document, &at)?;
+    }
+    Some(at)
+}
+
+fn word_end(document: &Document, at: &Position) -> Option<Position> {
+    let mut at = next(document, at)?;
+    while matches!(class(document, &at), Class::Blank | Class::EmptyRow) {
+        at = next(document, &at)?;
+    }
+    let current = class(document, &at);
+    while let Some(following) = next(document, &at) {
+        if class(document, &following) != current {
+            break;
+        }
+        at = following;
+    }
+    Some(at)
+}
+
+fn word_back(document: &Document, at: &Position) -> Option<Position> {
+    let mut at = previous(document, at)?;
+    while class(document, &at) == Class::Blank {
+        at = previous(document, &at)?;
+    }
+    let current = class(document, &at);
+    if current == Class::EmptyRow {
+        return Some(at);
+    }
+    while let Some(preceding) = previous(document, &at) {
+        if class(document, &preceding) != current {
+            break;
+        }
+        at = preceding;
+    }
+    Some(at)
+}
+
+// Where the motion repeated `count` times goes from `at`. Up and Down only
+// give the target row, the editor keeps the screen column itself
+pub fn target(document: &Document, at: &Position, motion: Motion, count: Option<usize>) -> Position {
+    let times = count.unwrap_or(1);
+    let len = document.row(at.y).map_or(0, crate::Row::len);
+    let last_row = document.len().saturating_sub(1);
+    let mut target = at.clone();
+
+    match motion {
+        Motion::Left => target.x = at.x.saturating_sub(times),
+        Motion::Right => target.x = std::cmp::min(at.x.saturating_add(times), len),
+        Motion::Up => target.y = at.y.saturating_sub(times),
+        Motion::Down => target.y = std::cmp::min(at.y.saturating_add(times), last_row),
+        Motion::LineStart => target.x = 0,
+        Motion::LineEnd => target.x = len.saturating_sub(1),
+        Motion::FileStart => target = Position { x: 0, y: count.map_or(0, |line| line.saturating_sub(1)).min(last_row) },
+        Motion::FileEnd => target = Position { x: 0, y: count.map_or(last_row, |line| line.saturating_sub(1)).min(last_row) },
+        Motion::WordStart | Motion::WordBack | Motion::WordEnd => {
+            let step = match motion {
+                Motion::WordStart => word_start,
+                Motion::WordBack => word_back,
+                _ => word_end,
+            };
+            for _ in 0..times {
+                target = match step(document, &target) {
+                    Some(position) => position,
+                    // Past the last word, stop at the end of the file
+                    None if motion == Motion::WordBack => Position::default(),
+                    None => Position {
+                        x: document.row(last_row).map_or(0, crate::Row::len),
+                        y: last_row,
+                    },
+                };
+            }
+        },
+    }
+    target
+}